@@ -0,0 +1,142 @@
+use crate::{
+  durable,
+  storage::{sha256_hex, NewRecord},
+};
+use std::{
+  collections::HashMap,
+  fs::{File, OpenOptions},
+  io::{self, Read, Seek, SeekFrom, Write},
+  path::{Path, PathBuf},
+  sync::Mutex,
+};
+
+/// `{name}-{date}.jsonl`, the log the record goes to
+pub fn log_filename(record: &NewRecord) -> String {
+  format!("{}-{}.jsonl", record.name, record.received_at.format("%Y-%m-%d"))
+}
+
+/// the line `append` writes for a record
+pub fn line(record: &NewRecord) -> io::Result<Vec<u8>> {
+  // the body is already encoded, so the envelope is spliced around it rather than decoded and encoded again
+  let mut line = serde_json::to_vec(&record.metadata(record.body.len() as u64, sha256_hex(&record.body)))?;
+  // reopen the object for the body
+  line.pop();
+  line.extend_from_slice(b",\"data\":");
+  line.extend_from_slice(&record.body);
+  line.extend_from_slice(b"}\n");
+  Ok(line)
+}
+
+/// keeps one open `.jsonl` file per name, writes to the same file are serialized so lines never interleave
+#[derive(Default)]
+pub struct Appenders {
+  files: Mutex<HashMap<String, (PathBuf, File)>>,
+}
+
+impl Appenders {
+  /// appends the record body wrapped in an envelope with its metadata as a single line to `{dir}/{name}-{date}.jsonl`
+  /// and fsyncs it, returns the filename, the bytes written and whether the file was created. the body has to be
+  /// compact JSON, a newline inside it would split the line
+  pub fn append(&self, dir: &Path, record: &NewRecord) -> io::Result<(String, u64, bool)> {
+    let filename = log_filename(record);
+    let path = dir.join(&filename);
+    let line = line(record)?;
+
+    let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+    // reopen when the date rolled over since the last write
+    let reuse = matches!(files.get(&record.name), Some((open_path, _)) if *open_path == path);
+    let mut created = false;
+    if !reuse {
+      created = !path.try_exists()?;
+      let mut file = OpenOptions::new().read(true).create(true).append(true).open(&path)?;
+      if created {
+        durable::sync_dir(dir)?;
+      } else {
+        repair_tail(&mut file)?;
+      }
+      files.insert(record.name.clone(), (path, file));
+    }
+    let (_, file) = files.get_mut(&record.name).expect("appender was just opened");
+    file.write_all(&line)?;
+    file.flush()?;
+    // the client is told the line is saved, so it has to survive a crash
+    file.sync_data()?;
+
+    Ok((filename, line.len() as u64, created))
+  }
+
+  /// removes a log, closing it first so later lines go to a new file rather than the removed one
+  pub fn remove(&self, path: &Path) -> io::Result<()> {
+    let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+    files.retain(|_, (open_path, _)| open_path != path);
+    std::fs::remove_file(path)
+  }
+
+  /// fsyncs every open log, a last check at shutdown as every line is synced when it is written
+  pub fn sync(&self) -> io::Result<()> {
+    let files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+    for (_, file) in files.values() {
+      file.sync_all()?;
+    }
+    Ok(())
+  }
+}
+
+/// cuts off a line left incomplete when a previous run died while writing it, it was never acknowledged
+fn repair_tail(file: &mut File) -> io::Result<()> {
+  const CHUNK: u64 = 8192;
+  let len = file.metadata()?.len();
+  let mut end = len;
+  let mut buf = vec![0; CHUNK as usize];
+  while end > 0 {
+    let start = end.saturating_sub(CHUNK);
+    let chunk = &mut buf[..(end - start) as usize];
+    file.seek(SeekFrom::Start(start))?;
+    file.read_exact(chunk)?;
+    if let Some(newline) = chunk.iter().rposition(|&b| b == b'\n') {
+      end = start + newline as u64 + 1;
+      break;
+    }
+    end = start;
+  }
+  if end < len {
+    tracing::warn!(bytes = len - end, "Removing an incomplete line from the end of an append log");
+    file.set_len(end)?;
+    file.sync_data()?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn repaired(content: &[u8]) -> Vec<u8> {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("log.jsonl");
+    std::fs::write(&path, content).unwrap();
+    let mut file = OpenOptions::new().read(true).append(true).open(&path).unwrap();
+    repair_tail(&mut file).unwrap();
+    file.write_all(b"next\n").unwrap();
+    std::fs::read(&path).unwrap()
+  }
+
+  #[test]
+  fn complete_logs_are_kept() {
+    assert_eq!(repaired(b""), b"next\n");
+    assert_eq!(repaired(b"{\"a\":1}\n{\"b\":2}\n"), b"{\"a\":1}\n{\"b\":2}\nnext\n");
+  }
+
+  #[test]
+  fn torn_lines_are_cut_off() {
+    assert_eq!(repaired(b"{\"a\":1}\n{\"b\":"), b"{\"a\":1}\nnext\n");
+    assert_eq!(repaired(b"{\"a\":"), b"next\n");
+  }
+
+  #[test]
+  fn torn_lines_longer_than_a_chunk_are_cut_off() {
+    let mut content = b"{\"a\":1}\n".to_vec();
+    content.extend(std::iter::repeat_n(b'x', 20000));
+    assert_eq!(repaired(&content), b"{\"a\":1}\nnext\n");
+  }
+}
@@ -0,0 +1,173 @@
+use crate::{
+  config::{matches_name, ApiKey, Permission},
+  error::AppError,
+  AppState,
+};
+use axum::{
+  extract::{RawPathParams, Request, State},
+  http::{header, HeaderMap, Method},
+  middleware::Next,
+  response::Response,
+};
+use std::sync::Arc;
+
+/// the api key a request was authenticated with, stored in request extensions
+#[derive(Clone, Debug)]
+pub struct Identity {
+  pub key_id: String,
+}
+
+/// middleware for routes that need an api key once `api_keys` are configured.
+/// routes with a `:name` need read permission for GET and write permission otherwise, routes without one need admin
+pub async fn authorize(
+  State(state): State<Arc<AppState>>,
+  params: RawPathParams,
+  mut req: Request,
+  next: Next,
+) -> Result<Response, AppError> {
+  let keys = &state.config.api_keys;
+  if keys.is_empty() {
+    return Ok(next.run(req).await);
+  }
+
+  let token = bearer_token(req.headers()).ok_or(AppError::Unauthorized)?;
+  let api_key = find_key(keys, token).ok_or(AppError::Unauthorized)?;
+
+  let name = params.iter().find(|(key, _)| *key == "name").map(|(_, value)| value);
+  let permission = required_permission(name, req.method());
+  if !allows(api_key, name, permission) {
+    return Err(AppError::Forbidden(api_key.id.clone()));
+  }
+
+  req.extensions_mut().insert(Identity {
+    key_id: api_key.id.clone(),
+  });
+  Ok(next.run(req).await)
+}
+
+/// the token of an `Authorization: Bearer` header, the scheme is case-insensitive
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+  let (scheme, token) = headers.get(header::AUTHORIZATION)?.to_str().ok()?.trim().split_once(' ')?;
+  let token = token.trim();
+  (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then_some(token)
+}
+
+fn required_permission(name: Option<&str>, method: &Method) -> Permission {
+  match (name, method) {
+    (None, _) => Permission::Admin,
+    (Some(_), &Method::GET | &Method::HEAD) => Permission::Read,
+    (Some(_), _) => Permission::Write,
+  }
+}
+
+/// every key is compared in full so the timing does not tell how much of a guess was right
+fn find_key<'a>(keys: &'a [ApiKey], token: &str) -> Option<&'a ApiKey> {
+  keys.iter().fold(None, |found, api_key| {
+    if constant_time_eq(api_key.key.as_bytes(), token.as_bytes()) {
+      Some(api_key)
+    } else {
+      found
+    }
+  })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn allows(api_key: &ApiKey, name: Option<&str>, permission: Permission) -> bool {
+  if !api_key.permissions.contains(&permission) {
+    return false;
+  }
+  match name {
+    None => true,
+    Some(name) => api_key.names.iter().any(|pattern| matches_name(pattern, name)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use axum::http::HeaderValue;
+
+  fn key(id: &str, key: &str, names: &[&str], permissions: &[Permission]) -> ApiKey {
+    ApiKey {
+      id: id.to_owned(),
+      key: key.to_owned(),
+      names: names.iter().map(|name| name.to_string()).collect(),
+      permissions: permissions.to_vec(),
+    }
+  }
+
+  fn token(authorization: &str) -> Option<String> {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::AUTHORIZATION, HeaderValue::from_str(authorization).unwrap());
+    bearer_token(&headers).map(str::to_owned)
+  }
+
+  #[test]
+  fn keys_match_only_in_full() {
+    let keys = [
+      key("reader", "secret-one", &["*"], &[Permission::Read]),
+      key("writer", "secret-two", &["*"], &[Permission::Write]),
+    ];
+    assert_eq!(find_key(&keys, "secret-two").map(|k| k.id.as_str()), Some("writer"));
+    assert!(find_key(&keys, "unknown").is_none());
+    // same length, one byte off
+    assert!(find_key(&keys, "secret-onf").is_none());
+    // a prefix, or the key with more after it
+    assert!(find_key(&keys, "secret-").is_none());
+    assert!(find_key(&keys, "secret-one2").is_none());
+    assert!(find_key(&keys, "").is_none());
+  }
+
+  #[test]
+  fn keys_allow_their_names_and_permissions() {
+    let exact = key("exact", "k", &["lab", "field"], &[Permission::Read, Permission::Write]);
+    assert!(allows(&exact, Some("lab"), Permission::Read));
+    assert!(allows(&exact, Some("field"), Permission::Write));
+    assert!(!allows(&exact, Some("lab2"), Permission::Read));
+    assert!(!allows(&exact, Some("la"), Permission::Read));
+    assert!(!allows(&exact, None, Permission::Admin));
+
+    let prefix = key("prefix", "k", &["sensor-*"], &[Permission::Read]);
+    assert!(allows(&prefix, Some("sensor-1"), Permission::Read));
+    assert!(allows(&prefix, Some("sensor-"), Permission::Read));
+    assert!(!allows(&prefix, Some("sensor"), Permission::Read));
+    assert!(!allows(&prefix, Some("sensor-1"), Permission::Write));
+
+    let every = key("every", "k", &["*"], &[Permission::Write]);
+    assert!(allows(&every, Some("anything"), Permission::Write));
+    assert!(!allows(&every, Some("anything"), Permission::Read));
+
+    // no names means no name, only admin routes
+    let admin = key("admin", "k", &[], &[Permission::Admin, Permission::Read]);
+    assert!(allows(&admin, None, Permission::Admin));
+    assert!(!allows(&admin, Some("lab"), Permission::Read));
+  }
+
+  #[test]
+  fn methods_map_to_permissions() {
+    assert_eq!(required_permission(Some("lab"), &Method::GET), Permission::Read);
+    assert_eq!(required_permission(Some("lab"), &Method::HEAD), Permission::Read);
+    for method in [Method::POST, Method::PUT, Method::DELETE, Method::PATCH] {
+      assert_eq!(required_permission(Some("lab"), &method), Permission::Write);
+    }
+    // routes without a `:name`, like `/stats`, whatever the method
+    assert_eq!(required_permission(None, &Method::GET), Permission::Admin);
+    assert_eq!(required_permission(None, &Method::POST), Permission::Admin);
+  }
+
+  #[test]
+  fn bearer_tokens_are_parsed_from_the_header() {
+    assert_eq!(token("Bearer abc").as_deref(), Some("abc"));
+    assert_eq!(token("bearer abc").as_deref(), Some("abc"));
+    assert_eq!(token("BEARER abc").as_deref(), Some("abc"));
+    assert_eq!(token("Bearer  abc ").as_deref(), Some("abc"));
+    assert_eq!(token("Basic YWJjOmRlZg=="), None);
+    assert_eq!(token("Bearerabc"), None);
+    assert_eq!(token("Bearer"), None);
+    assert_eq!(token("Bearer   "), None);
+    assert_eq!(bearer_token(&HeaderMap::new()), None);
+  }
+}
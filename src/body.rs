@@ -0,0 +1,411 @@
+use crate::{
+  config::{OutputFormat, SaveMode},
+  error::AppError,
+  quota::Reservation,
+  storage::{BodyKind, NewRecord, RecordMeta, Storage},
+};
+use axum::{
+  body::{Body, Bytes},
+  http::{header, HeaderMap, StatusCode},
+};
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use serde::{
+  de::{MapAccess, SeqAccess, Visitor},
+  Deserialize, Deserializer,
+};
+use serde_json::Value;
+use std::{
+  borrow::Cow,
+  fmt,
+  io::{self, BufReader, Read},
+  sync::{mpsc as sync_mpsc, Arc},
+};
+use tokio::sync::mpsc;
+
+/// why a request body was refused while reading it
+#[derive(Debug)]
+pub enum BodyError {
+  /// carries the limit that was exceeded
+  TooLarge(u64),
+  InvalidJson(serde_json::Error),
+  /// the connection failed while the body was being received
+  Read(String),
+  /// storing the body would go past the described quota
+  OverQuota(String),
+}
+
+impl fmt::Display for BodyError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      BodyError::TooLarge(limit) => write!(f, "body is larger than {} bytes", limit),
+      BodyError::InvalidJson(e) => write!(f, "invalid JSON: {}", e),
+      BodyError::Read(e) => write!(f, "failed to read body: {}", e),
+      BodyError::OverQuota(quota) => write!(f, "body would exceed {}", quota),
+    }
+  }
+}
+
+impl std::error::Error for BodyError {}
+
+impl From<BodyError> for AppError {
+  fn from(e: BodyError) -> Self {
+    match e {
+      BodyError::TooLarge(limit) => AppError::PayloadTooLarge(limit),
+      BodyError::OverQuota(quota) => AppError::QuotaExceeded(quota),
+      e => AppError::InvalidBody {
+        status: StatusCode::BAD_REQUEST,
+        message: e.to_string(),
+      },
+    }
+  }
+}
+
+/// the declared `Content-Length`, if any
+pub fn content_length(headers: &HeaderMap) -> Option<u64> {
+  headers.get(header::CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+/// buffers the whole body, failing as soon as it grows past `limit`
+pub async fn read_limited(body: Body, limit: u64) -> Result<Bytes, BodyError> {
+  match Limited::new(body, limit as usize).collect().await {
+    Ok(collected) => Ok(collected.to_bytes()),
+    Err(e) if e.is::<LengthLimitError>() => Err(BodyError::TooLarge(limit)),
+    Err(e) => Err(BodyError::Read(e.to_string())),
+  }
+}
+
+pub fn parse_json(bytes: &[u8]) -> Result<Value, AppError> {
+  serde_json::from_slice(bytes).map_err(|e| AppError::InvalidBody {
+    status: StatusCode::BAD_REQUEST,
+    message: format!("Failed to parse the request body as JSON: {}", e),
+  })
+}
+
+/// checks the syntax without building a tree
+pub fn check_json(bytes: &[u8]) -> Result<(), AppError> {
+  match serde_json::from_slice::<Syntax>(bytes) {
+    Ok(Syntax) => Ok(()),
+    Err(e) => Err(BodyError::InvalidJson(e).into()),
+  }
+}
+
+/// checks the syntax of the document `reader` yields, to its end
+fn check_syntax(reader: impl Read) -> Result<(), serde_json::Error> {
+  let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(reader));
+  Syntax::deserialize(&mut deserializer)?;
+  deserializer.end()
+}
+
+/// any JSON value, checked by serde_json like `parse_json` does but without keeping it: strings and numbers are
+/// still decoded, so lone surrogates and numbers out of range are refused like they are when a tree is built
+struct Syntax;
+
+impl<'de> Deserialize<'de> for Syntax {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    deserializer.deserialize_any(Syntax)
+  }
+}
+
+impl<'de> Visitor<'de> for Syntax {
+  type Value = Syntax;
+
+  fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("a JSON value")
+  }
+
+  fn visit_bool<E>(self, _: bool) -> Result<Syntax, E> {
+    Ok(Syntax)
+  }
+
+  fn visit_i64<E>(self, _: i64) -> Result<Syntax, E> {
+    Ok(Syntax)
+  }
+
+  fn visit_u64<E>(self, _: u64) -> Result<Syntax, E> {
+    Ok(Syntax)
+  }
+
+  fn visit_f64<E>(self, _: f64) -> Result<Syntax, E> {
+    Ok(Syntax)
+  }
+
+  fn visit_str<E>(self, _: &str) -> Result<Syntax, E> {
+    Ok(Syntax)
+  }
+
+  fn visit_unit<E>(self) -> Result<Syntax, E> {
+    Ok(Syntax)
+  }
+
+  fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Syntax, A::Error> {
+    while seq.next_element::<Syntax>()?.is_some() {}
+    Ok(Syntax)
+  }
+
+  fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Syntax, A::Error> {
+    while map.next_entry::<Syntax, Syntax>()?.is_some() {}
+    Ok(Syntax)
+  }
+}
+
+/// bytes to store for a body in `format`, `payload` is the parsed body when it was needed for anything else.
+/// appended lines have to stay on one line, so append mode compacts pretty output and raw bodies with line breaks
+pub fn encode(raw: Bytes, payload: Option<&Value>, format: OutputFormat, mode: SaveMode) -> Result<Vec<u8>, AppError> {
+  let format = match (mode, format) {
+    (SaveMode::Append, OutputFormat::Pretty) => OutputFormat::Compact,
+    (SaveMode::Append, OutputFormat::Raw) if raw.contains(&b'\n') || raw.contains(&b'\r') => OutputFormat::Compact,
+    (_, format) => format,
+  };
+  if format == OutputFormat::Raw {
+    return Ok(raw.into());
+  }
+
+  let payload = match payload {
+    Some(payload) => Cow::Borrowed(payload),
+    None => Cow::Owned(parse_json(&raw)?),
+  };
+  match format {
+    OutputFormat::Pretty => serde_json::to_vec_pretty(&*payload),
+    _ => serde_json::to_vec(&*payload),
+  }
+  .map_err(|e| AppError::io("encoding payload")(e.into()))
+}
+
+/// hands the body to storage chunk by chunk, checking on the way that JSON records are well-formed and that the body
+/// fits `limit` and the quotas, growing `reservation` as it arrives and keeping it once stored, so neither the raw bytes nor a parsed tree of a large document are
+/// held in memory
+pub async fn store_streaming(
+  storage: Arc<dyn Storage>,
+  record: NewRecord,
+  mut body: Body,
+  limit: u64,
+  reservation: Reservation,
+) -> Result<RecordMeta, AppError> {
+  let (tx, rx) = mpsc::channel(8);
+  // documents are parsed on their own thread from a copy of each chunk, as storage pulls the body at its own pace
+  let syntax = (record.kind == BodyKind::Json).then(|| {
+    let (chunks, chunks_rx) = sync_mpsc::sync_channel(8);
+    let (result_tx, result) = sync_mpsc::channel();
+    tokio::task::spawn_blocking(move || {
+      let _ = result_tx.send(check_syntax(ChunkReader {
+        rx: chunks_rx,
+        current: Bytes::new(),
+      }));
+    });
+    SyntaxCheck {
+      chunks: Some(chunks),
+      result,
+    }
+  });
+  let task = tokio::task::spawn_blocking(move || {
+    let mut reader = CheckedReader {
+      rx,
+      current: Bytes::new(),
+      syntax,
+      received: 0,
+      limit,
+      reservation,
+    };
+    let meta = storage.put_reader(&record, &mut reader)?;
+    reader.reservation.keep(meta.size, 1);
+    io::Result::Ok(meta)
+  });
+
+  while let Some(frame) = body.frame().await {
+    let chunk = match frame {
+      Ok(frame) => match frame.into_data() {
+        Ok(data) => Ok(data),
+        // trailers
+        Err(_) => continue,
+      },
+      Err(e) => Err(BodyError::Read(e.to_string())),
+    };
+    let failed = chunk.is_err();
+    // a closed channel means storage already gave up, its error is reported below
+    if tx.send(chunk).await.is_err() || failed {
+      break;
+    }
+  }
+  drop(tx);
+
+  let result = task.await.map_err(|e| AppError::io("saving record")(io::Error::other(e)))?;
+  result.map_err(|e| match e.get_ref().is_some_and(|inner| inner.is::<BodyError>()) {
+    true => (*e
+      .into_inner()
+      .expect("checked above")
+      .downcast::<BodyError>()
+      .expect("checked above"))
+    .into(),
+    false => AppError::io("saving record")(e),
+  })
+}
+
+/// blocking reader over body chunks sent from the async side, fails with a `BodyError` as soon as the
+/// limit or the quota is exceeded or the JSON turns out to be malformed, which makes storage drop the partial record
+struct CheckedReader {
+  rx: mpsc::Receiver<Result<Bytes, BodyError>>,
+  current: Bytes,
+  /// `None` for binary bodies
+  syntax: Option<SyntaxCheck>,
+  received: u64,
+  limit: u64,
+  /// covers the bytes received so far
+  reservation: Reservation,
+}
+
+impl Read for CheckedReader {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    while self.current.is_empty() {
+      match self.rx.blocking_recv() {
+        Some(Ok(chunk)) => {
+          self.received += chunk.len() as u64;
+          if self.received > self.limit {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, BodyError::TooLarge(self.limit)));
+          }
+          if let Err(quota) = self.reservation.cover(self.received) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, BodyError::OverQuota(quota)));
+          }
+          if let Some(syntax) = &mut self.syntax {
+            syntax.feed(chunk.clone())?;
+          }
+          self.current = chunk;
+        }
+        Some(Err(e)) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        None => {
+          if let Some(syntax) = &mut self.syntax {
+            syntax.finish()?;
+          }
+          return Ok(0);
+        }
+      }
+    }
+
+    let n = buf.len().min(self.current.len());
+    buf[..n].copy_from_slice(&self.current.split_to(n));
+    Ok(n)
+  }
+}
+
+/// the syntax check running next to a `CheckedReader`
+struct SyntaxCheck {
+  /// dropped once the body is complete
+  chunks: Option<sync_mpsc::SyncSender<Bytes>>,
+  result: sync_mpsc::Receiver<Result<(), serde_json::Error>>,
+}
+
+impl SyntaxCheck {
+  fn feed(&mut self, chunk: Bytes) -> io::Result<()> {
+    let sent = self.chunks.as_ref().is_some_and(|chunks| chunks.send(chunk).is_ok());
+    // the parser only hangs up early when the document is malformed
+    if !sent {
+      self.finish()?;
+    }
+    Ok(())
+  }
+
+  /// waits for the parser to reach the end of the body
+  fn finish(&mut self) -> io::Result<()> {
+    self.chunks = None;
+    match self.result.recv() {
+      Ok(Ok(())) => Ok(()),
+      Ok(Err(e)) => Err(io::Error::new(io::ErrorKind::InvalidData, BodyError::InvalidJson(e))),
+      Err(_) => Err(io::Error::other("syntax check stopped")),
+    }
+  }
+}
+
+/// blocking reader over the chunks sent to a `SyntaxCheck`
+struct ChunkReader {
+  rx: sync_mpsc::Receiver<Bytes>,
+  current: Bytes,
+}
+
+impl Read for ChunkReader {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    while self.current.is_empty() {
+      match self.rx.recv() {
+        Ok(chunk) => self.current = chunk,
+        Err(_) => return Ok(0),
+      }
+    }
+    let n = buf.len().min(self.current.len());
+    buf[..n].copy_from_slice(&self.current.split_to(n));
+    Ok(n)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const VALID: &[&str] = &[
+    "{}",
+    " [1, -2.5e3, true, false, null, \"a\\u00e9\\ud83d\\ude00\"] ",
+    "{\"a\": {\"b\": [[], {}]}}",
+    "0",
+    "1E308",
+    "\"\\\"\"",
+  ];
+  const INVALID: &[&str] = &[
+    "",
+    "{",
+    "[1,]",
+    "{\"a\" 1}",
+    "01",
+    "1.",
+    "tru",
+    "\"\\ud800\"",
+    "\"\\udc00x\"",
+    "1E320",
+    "-1e400",
+    "\"\\x\"",
+    "{} {}",
+    "\"unterminated",
+  ];
+
+  #[test]
+  fn check_json_agrees_with_parse_json() {
+    for document in VALID.iter().chain(INVALID) {
+      assert_eq!(
+        check_json(document.as_bytes()).is_ok(),
+        parse_json(document.as_bytes()).is_ok(),
+        "{}",
+        document
+      );
+    }
+    for document in VALID {
+      assert!(check_json(document.as_bytes()).is_ok(), "{}", document);
+    }
+    for document in INVALID {
+      assert!(check_json(document.as_bytes()).is_err(), "{}", document);
+    }
+  }
+
+  #[test]
+  fn invalid_utf8_is_refused() {
+    assert!(check_json(b"\"\xff\"").is_err());
+    assert!(check_syntax(&b"\"\xc3\""[..]).is_err());
+  }
+
+  /// the document split at every byte, as chunks of a streamed body may be
+  #[test]
+  fn chunk_boundaries_do_not_matter() {
+    for document in VALID.iter().chain(INVALID).chain(&["\"\u{e9}\u{1f600}\""]) {
+      let bytes = document.as_bytes();
+      for split in 0..=bytes.len() {
+        let (chunks, rx) = sync_mpsc::sync_channel(2);
+        chunks.send(Bytes::copy_from_slice(&bytes[..split])).unwrap();
+        chunks.send(Bytes::copy_from_slice(&bytes[split..])).unwrap();
+        drop(chunks);
+        let reader = ChunkReader { rx, current: Bytes::new() };
+        assert_eq!(
+          check_syntax(reader).is_ok(),
+          check_json(bytes).is_ok(),
+          "{} split at {}",
+          document,
+          split
+        );
+      }
+    }
+  }
+}
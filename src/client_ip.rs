@@ -0,0 +1,266 @@
+use crate::{config::ForwardedHeader, AppState};
+use axum::{
+  async_trait,
+  extract::{ConnectInfo, FromRequestParts},
+  http::{request::Parts, HeaderMap},
+};
+use core::net::SocketAddr;
+use ipnet::IpNet;
+use std::{convert::Infallible, net::IpAddr, sync::Arc};
+
+/// resolves the address of the client behind any trusted reverse proxies
+#[derive(Debug, Clone, Default)]
+pub struct ClientIpResolver {
+  trusted: Vec<IpNet>,
+  header: ForwardedHeader,
+}
+
+impl ClientIpResolver {
+  pub fn new(trusted: Vec<IpNet>, header: ForwardedHeader) -> Self {
+    ClientIpResolver { trusted, header }
+  }
+
+  fn is_trusted(&self, ip: IpAddr) -> bool {
+    self.trusted.iter().any(|net| net.contains(&ip))
+  }
+
+  /// the forwarding header is only believed when the socket peer is a trusted proxy, and only the one the proxies set:
+  /// the others come from the client as they are. hops are walked right to left, the first one that is not a trusted
+  /// proxy is the client
+  pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    let peer = peer.to_canonical();
+    if !self.is_trusted(peer) {
+      return peer;
+    }
+
+    let hops: Vec<_> = match self.header {
+      ForwardedHeader::Forwarded => forwarded_hops(headers),
+      ForwardedHeader::XForwardedFor => header_values(headers, self.header.name())
+        .flat_map(|v| v.split(','))
+        .map(parse_node)
+        .collect(),
+      // a single address, when a client sends one too the proxy's comes last
+      ForwardedHeader::XRealIp => header_values(headers, self.header.name())
+        .next_back()
+        .map(parse_node)
+        .into_iter()
+        .collect(),
+    };
+
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+      match hop {
+        Some(ip) => {
+          client = ip;
+          if !self.is_trusted(ip) {
+            break;
+          }
+        }
+        // an obfuscated or garbled hop, nothing left of it can be trusted
+        None => break,
+      }
+    }
+    client
+  }
+}
+
+/// all values of a header in order
+fn header_values<'a>(headers: &'a HeaderMap, name: &str) -> impl DoubleEndedIterator<Item = &'a str> {
+  headers.get_all(name).iter().filter_map(|v| v.to_str().ok())
+}
+
+/// `for=` parameters of RFC 7239 `Forwarded` headers, one entry per hop
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+  header_values(headers, "forwarded")
+    .flat_map(|v| v.split(','))
+    .map(|element| {
+      element.split(';').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        key.trim().eq_ignore_ascii_case("for").then_some(value)
+      })
+    })
+    .map(|value| value.and_then(parse_node))
+    .collect()
+}
+
+/// parses `1.2.3.4`, `1.2.3.4:80`, `::1`, `[::1]` and `[::1]:80`, optionally quoted
+fn parse_node(node: &str) -> Option<IpAddr> {
+  let node = node.trim().trim_matches('"');
+  if let Ok(ip) = node.parse::<IpAddr>() {
+    return Some(ip.to_canonical());
+  }
+  if let Ok(addr) = node.parse::<SocketAddr>() {
+    return Some(addr.ip().to_canonical());
+  }
+  let bracketed = node.strip_prefix('[')?.strip_suffix(']')?;
+  bracketed.parse::<IpAddr>().ok().map(|ip| ip.to_canonical())
+}
+
+/// extractor for the resolved client address
+pub struct ClientIp {
+  pub ip: IpAddr,
+  /// the socket address the request came from, which is a proxy when forwarding headers were believed
+  pub peer: SocketAddr,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for ClientIp {
+  type Rejection = Infallible;
+
+  async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+    let peer = parts
+      .extensions
+      .get::<ConnectInfo<SocketAddr>>()
+      .map(|&ConnectInfo(addr)| addr)
+      .unwrap_or(SocketAddr::from(([0, 0, 0, 0], 0)));
+    Ok(ClientIp {
+      ip: state.client_ip.resolve(peer.ip(), &parts.headers),
+      peer,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use axum::http::HeaderValue;
+
+  const PROXY: &str = "10.0.0.1";
+
+  fn resolver(header: ForwardedHeader) -> ClientIpResolver {
+    ClientIpResolver::new(vec!["10.0.0.0/8".parse().unwrap()], header)
+  }
+
+  fn header_map(pairs: &[(&'static str, &str)]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+      headers.append(*name, HeaderValue::from_str(value).unwrap());
+    }
+    headers
+  }
+
+  fn ip(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+  }
+
+  #[test]
+  fn parse_node_forms() {
+    assert_eq!(parse_node("1.2.3.4"), Some(ip("1.2.3.4")));
+    assert_eq!(parse_node(" 1.2.3.4:8080 "), Some(ip("1.2.3.4")));
+    assert_eq!(parse_node("\"1.2.3.4\""), Some(ip("1.2.3.4")));
+    assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+    assert_eq!(parse_node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+    assert_eq!(parse_node("\"[2001:db8::1]:443\""), Some(ip("2001:db8::1")));
+    assert_eq!(parse_node("::ffff:1.2.3.4"), Some(ip("1.2.3.4")));
+  }
+
+  #[test]
+  fn parse_node_garbled() {
+    assert_eq!(parse_node(""), None);
+    assert_eq!(parse_node("unknown"), None);
+    assert_eq!(parse_node("_hidden"), None);
+    assert_eq!(parse_node("1.2.3"), None);
+    assert_eq!(parse_node("[1.2.3.4"), None);
+    assert_eq!(parse_node("[2001:db8::1]:port"), None);
+  }
+
+  #[test]
+  fn untrusted_peer_ignores_headers() {
+    let headers = header_map(&[("x-forwarded-for", "203.0.113.9"), ("forwarded", "for=6.6.6.6")]);
+    let client = resolver(ForwardedHeader::XForwardedFor).resolve(ip("192.0.2.1"), &headers);
+    assert_eq!(client, ip("192.0.2.1"));
+  }
+
+  #[test]
+  fn other_headers_cannot_spoof() {
+    let headers = header_map(&[
+      ("x-forwarded-for", "203.0.113.9"),
+      ("forwarded", "for=6.6.6.6"),
+      ("x-real-ip", "7.7.7.7"),
+    ]);
+    assert_eq!(
+      resolver(ForwardedHeader::XForwardedFor).resolve(ip(PROXY), &headers),
+      ip("203.0.113.9")
+    );
+
+    let headers = header_map(&[("x-real-ip", "203.0.113.9"), ("x-forwarded-for", "6.6.6.6")]);
+    assert_eq!(resolver(ForwardedHeader::XRealIp).resolve(ip(PROXY), &headers), ip("203.0.113.9"));
+
+    let headers = header_map(&[("forwarded", "for=203.0.113.9"), ("x-forwarded-for", "6.6.6.6")]);
+    assert_eq!(resolver(ForwardedHeader::Forwarded).resolve(ip(PROXY), &headers), ip("203.0.113.9"));
+  }
+
+  #[test]
+  fn missing_header_is_the_peer() {
+    let headers = header_map(&[("forwarded", "for=6.6.6.6")]);
+    assert_eq!(resolver(ForwardedHeader::XForwardedFor).resolve(ip(PROXY), &headers), ip(PROXY));
+  }
+
+  #[test]
+  fn multiple_hops() {
+    // the client prepended a made up hop, the proxies appended theirs
+    let headers = header_map(&[("x-forwarded-for", "6.6.6.6, 203.0.113.9, 10.0.0.2")]);
+    assert_eq!(
+      resolver(ForwardedHeader::XForwardedFor).resolve(ip(PROXY), &headers),
+      ip("203.0.113.9")
+    );
+
+    // a header repeated on several lines counts as one list
+    let headers = header_map(&[("x-forwarded-for", "6.6.6.6, 203.0.113.9"), ("x-forwarded-for", "10.0.0.2")]);
+    assert_eq!(
+      resolver(ForwardedHeader::XForwardedFor).resolve(ip(PROXY), &headers),
+      ip("203.0.113.9")
+    );
+
+    let headers = header_map(&[("forwarded", "for=6.6.6.6, for=203.0.113.9;proto=https, for=10.0.0.2")]);
+    assert_eq!(resolver(ForwardedHeader::Forwarded).resolve(ip(PROXY), &headers), ip("203.0.113.9"));
+
+    // every hop trusted, the leftmost one is as far as it goes
+    let headers = header_map(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+    assert_eq!(
+      resolver(ForwardedHeader::XForwardedFor).resolve(ip(PROXY), &headers),
+      ip("10.0.0.3")
+    );
+  }
+
+  #[test]
+  fn garbled_hop_stops_the_walk() {
+    let headers = header_map(&[("x-forwarded-for", "6.6.6.6, garbage, 10.0.0.2")]);
+    assert_eq!(
+      resolver(ForwardedHeader::XForwardedFor).resolve(ip(PROXY), &headers),
+      ip("10.0.0.2")
+    );
+
+    let headers = header_map(&[("forwarded", "for=6.6.6.6, for=_hidden")]);
+    assert_eq!(resolver(ForwardedHeader::Forwarded).resolve(ip(PROXY), &headers), ip(PROXY));
+
+    // an element without `for=`
+    let headers = header_map(&[("forwarded", "for=6.6.6.6, proto=https")]);
+    assert_eq!(resolver(ForwardedHeader::Forwarded).resolve(ip(PROXY), &headers), ip(PROXY));
+  }
+
+  #[test]
+  fn ipv6_hops() {
+    let headers = header_map(&[("forwarded", "for=\"[2001:db8::7]:4711\", for=10.0.0.2")]);
+    assert_eq!(resolver(ForwardedHeader::Forwarded).resolve(ip(PROXY), &headers), ip("2001:db8::7"));
+
+    let headers = header_map(&[("x-forwarded-for", "2001:db8::7, [2001:db8::8]")]);
+    assert_eq!(
+      resolver(ForwardedHeader::XForwardedFor).resolve(ip(PROXY), &headers),
+      ip("2001:db8::8")
+    );
+
+    // a proxy reached over IPv6 as a mapped IPv4 address is still trusted
+    let headers = header_map(&[("x-forwarded-for", "203.0.113.9")]);
+    assert_eq!(
+      resolver(ForwardedHeader::XForwardedFor).resolve(ip("::ffff:10.0.0.1"), &headers),
+      ip("203.0.113.9")
+    );
+  }
+
+  #[test]
+  fn x_real_ip_takes_the_last_value() {
+    let headers = header_map(&[("x-real-ip", "6.6.6.6"), ("x-real-ip", "203.0.113.9")]);
+    assert_eq!(resolver(ForwardedHeader::XRealIp).resolve(ip(PROXY), &headers), ip("203.0.113.9"));
+  }
+}
@@ -0,0 +1,663 @@
+use clap::{Parser, ValueEnum};
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+  collections::HashMap,
+  fmt, fs,
+  net::{IpAddr, Ipv4Addr, SocketAddr},
+  path::{Path, PathBuf},
+};
+
+/// command line flags, each one can also be set from the environment and overrides the config file
+#[derive(Parser, Debug)]
+#[command(version, about = "tiny server to save some data")]
+struct Cli {
+  /// optional TOML config file
+  #[arg(long, env = "DATA_BACKS_CONFIG")]
+  config: Option<PathBuf>,
+  /// directory where records are saved
+  #[arg(long, env = "DATA_BACKS_DATA_DIR")]
+  data_dir: Option<PathBuf>,
+  /// address to listen on
+  #[arg(long, env = "DATA_BACKS_BIND")]
+  bind: Option<IpAddr>,
+  /// port to listen on
+  #[arg(long, env = "PORT")]
+  port: Option<u16>,
+  /// storage backend
+  #[arg(long, env = "DATA_BACKS_STORAGE", value_enum)]
+  storage: Option<StorageBackend>,
+  /// how log lines are written
+  #[arg(long, env = "DATA_BACKS_LOG_FORMAT", value_enum)]
+  log_format: Option<LogFormat>,
+}
+
+/// how `save_data` stores a payload
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SaveMode {
+  /// one `.json` file per request
+  #[default]
+  File,
+  /// one line per request in a daily `.jsonl` file per name
+  Append,
+}
+
+#[derive(Deserialize, Serialize, ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+  /// one JSON file per record under `data_dir`
+  #[default]
+  Filesystem,
+  /// a single SQLite database
+  Sqlite,
+}
+
+/// how the filesystem backend compresses records at rest
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+  #[default]
+  None,
+  /// `.json.gz` files
+  Gzip,
+  /// `.json.zst` files
+  Zstd,
+}
+
+/// `[storage]` in the config file
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+  pub backend: StorageBackend,
+  /// database file for the SQLite backend, defaults to `records.sqlite3` inside `data_dir`
+  pub sqlite_path: Option<PathBuf>,
+  /// only used by the filesystem backend for new records, records stored with any setting stay readable
+  pub compression: Compression,
+}
+
+impl StorageConfig {
+  pub fn sqlite_path(&self, data_dir: &Path) -> PathBuf {
+    self.sqlite_path.clone().unwrap_or_else(|| data_dir.join("records.sqlite3"))
+  }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+  /// list and fetch records
+  Read,
+  /// save and delete records
+  Write,
+  /// endpoints not tied to a name, like `/stats`
+  Admin,
+}
+
+/// `[[api_keys]]` in the config file, sent by clients as `Authorization: Bearer {key}`
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+  /// label shown in logs instead of the key itself
+  pub id: String,
+  pub key: String,
+  /// exact names, or prefixes ending with `*`; a lone `*` matches every name
+  #[serde(default)]
+  pub names: Vec<String>,
+  pub permissions: Vec<Permission>,
+}
+
+impl fmt::Debug for ApiKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("ApiKey")
+      .field("id", &self.id)
+      .field("names", &self.names)
+      .field("permissions", &self.permissions)
+      .finish_non_exhaustive()
+  }
+}
+
+/// how a JSON body is written to storage
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+  /// re-encoded with indentation
+  #[default]
+  Pretty,
+  /// re-encoded without whitespace
+  Compact,
+  /// the bytes the client sent, checked to be JSON but not re-encoded
+  Raw,
+}
+
+/// the forwarding header the trusted proxies set, only that one is read
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+  /// RFC 7239 `Forwarded`, the `for=` of each hop
+  Forwarded,
+  /// a comma separated list of addresses, proxies append the address they got the request from
+  #[default]
+  XForwardedFor,
+  /// a single address, set by the proxy in front of the server
+  XRealIp,
+}
+
+impl ForwardedHeader {
+  pub fn name(self) -> &'static str {
+    match self {
+      ForwardedHeader::Forwarded => "forwarded",
+      ForwardedHeader::XForwardedFor => "x-forwarded-for",
+      ForwardedHeader::XRealIp => "x-real-ip",
+    }
+  }
+}
+
+/// a token bucket, refilled with `rate` requests per second up to `burst`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+  pub rate: f64,
+  pub burst: u32,
+}
+
+/// `[rate_limits]` in the config file, a request has to fit every limit that applies to it
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+  /// per resolved client address, IPv6 clients are grouped by /64
+  pub per_ip: Option<RateLimit>,
+  /// per api key
+  pub per_key: Option<RateLimit>,
+  /// per data name, can be overridden by `[names.{name}] rate_limit`
+  pub per_name: Option<RateLimit>,
+  /// buckets kept for each of the above, once full new clients share one bucket until idle ones are dropped
+  pub max_tracked: usize,
+}
+
+impl Default for RateLimits {
+  fn default() -> Self {
+    RateLimits {
+      per_ip: None,
+      per_key: None,
+      per_name: None,
+      max_tracked: 10_000,
+    }
+  }
+}
+
+/// `[tls]` in the config file, the server speaks HTTPS instead of HTTP when it is present
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+  /// PEM certificate chain, leaf first
+  pub cert: PathBuf,
+  /// PEM private key of the leaf certificate
+  pub key: PathBuf,
+  /// PEM certificates of the CAs client certificates are verified against, turns on mutual TLS
+  pub client_ca: Option<PathBuf>,
+  /// with `client_ca`, whether connections without a client certificate are refused
+  pub require_client_cert: bool,
+  /// port of a plain HTTP listener that redirects every request to HTTPS, none when unset
+  pub redirect_port: Option<u16>,
+  /// seconds between checks of the certificate files for changes, SIGHUP reloads them right away
+  pub reload_interval: u64,
+}
+
+impl Default for TlsConfig {
+  fn default() -> Self {
+    TlsConfig {
+      cert: PathBuf::new(),
+      key: PathBuf::new(),
+      client_ca: None,
+      require_client_cert: true,
+      redirect_port: None,
+      reload_interval: 10,
+    }
+  }
+}
+
+/// how log lines are written
+#[derive(Deserialize, ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+  /// one human readable line per event
+  #[default]
+  Text,
+  /// several indented lines per event, for reading in a terminal
+  Pretty,
+  /// one JSON object per line, with the fields of the request span
+  Json,
+}
+
+/// `[log]` in the config file
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+  pub format: LogFormat,
+  /// `tracing` filter directives, a default level then levels per module like `data_backs::storage=debug`.
+  /// `RUST_LOG` replaces it when set
+  pub filter: String,
+}
+
+impl Default for LogConfig {
+  fn default() -> Self {
+    LogConfig {
+      format: LogFormat::default(),
+      filter: "info".to_owned(),
+    }
+  }
+}
+
+/// `[metrics]` in the config file, keeps the number of series bounded whatever names clients use
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+  /// names, or prefixes ending with `*`, that get their own `name` label. when empty the first `max_names` names seen
+  /// get one
+  pub names: Vec<String>,
+  /// the most names with their own label, all others are counted under `_other`
+  pub max_names: usize,
+}
+
+impl Default for MetricsConfig {
+  fn default() -> Self {
+    MetricsConfig {
+      names: vec![],
+      max_names: 100,
+    }
+  }
+}
+
+/// `[[webhooks.endpoints]]` in the config file, notified of every record saved under one of `names`
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookEndpoint {
+  /// label used in logs and dead letters, deliveries find their endpoint by it
+  pub id: String,
+  pub url: String,
+  /// HMAC-SHA256 key the `X-Data-Backs-Signature` header is computed with
+  pub secret: String,
+  /// exact names, or prefixes ending with `*`; a lone `*` matches every name
+  pub names: Vec<String>,
+  /// whether JSON payloads are sent along with the metadata
+  #[serde(default)]
+  pub include_payload: bool,
+}
+
+impl fmt::Debug for WebhookEndpoint {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("WebhookEndpoint")
+      .field("id", &self.id)
+      .field("url", &self.url)
+      .field("names", &self.names)
+      .field("include_payload", &self.include_payload)
+      .finish_non_exhaustive()
+  }
+}
+
+/// `[webhooks]` in the config file, deliveries are queued in `data_dir` and retried with exponential backoff
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+  pub endpoints: Vec<WebhookEndpoint>,
+  /// attempts before a delivery goes to the dead letters
+  pub max_attempts: u32,
+  /// seconds before the first retry, doubled after every failed attempt
+  pub initial_backoff: u64,
+  /// longest wait between two attempts, in seconds
+  pub max_backoff: u64,
+  /// seconds an endpoint gets to answer
+  pub timeout: u64,
+  /// deliveries sent at the same time
+  pub concurrency: usize,
+  /// dead letters kept, the oldest are dropped past it
+  pub max_dead: usize,
+}
+
+impl Default for WebhooksConfig {
+  fn default() -> Self {
+    WebhooksConfig {
+      endpoints: vec![],
+      max_attempts: 10,
+      initial_backoff: 10,
+      max_backoff: 60 * 60,
+      timeout: 10,
+      concurrency: 8,
+      max_dead: 10_000,
+    }
+  }
+}
+
+/// what the retention task does with records older than `max_age`
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnExpiry {
+  #[default]
+  Delete,
+  /// moved to `archive_dir` as files, whatever the storage backend
+  Archive,
+}
+
+/// settings for a single data name, `[names.{name}]` in the config file
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NameConfig {
+  /// used when the request has no `?mode=`
+  pub mode: SaveMode,
+  /// overrides the global `max_body_size`
+  pub max_body_size: Option<u64>,
+  /// overrides the global `format`
+  pub format: Option<OutputFormat>,
+  /// seconds during which a JSON payload identical to a stored one is answered with the stored record, off when unset
+  pub dedup_window: Option<u64>,
+  /// overrides `rate_limits.per_name`
+  pub rate_limit: Option<RateLimit>,
+  /// stored bytes allowed for the name, saves past it get 507
+  pub max_bytes: Option<u64>,
+  /// records allowed for the name, saves past it get 507
+  pub max_records: Option<u64>,
+  /// seconds a record is kept before the retention task removes it, forever when unset
+  pub max_age: Option<u64>,
+  pub on_expiry: OnExpiry,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  pub data_dir: PathBuf,
+  pub bind: IpAddr,
+  pub port: u16,
+  pub storage: StorageConfig,
+  /// how bodies are written, bodies that are streamed are always stored raw
+  pub format: OutputFormat,
+  /// largest accepted request body in bytes, larger ones get 413
+  pub max_body_size: u64,
+  /// bodies larger than this, or without a `Content-Length`, are written to storage while they arrive
+  /// instead of being parsed in memory first. only applies to names saved raw in file mode, without a schema or dedup
+  pub stream_threshold: u64,
+  /// JSON Schemas as `{name}.json`, payloads of names with a schema are validated against it
+  pub schemas_dir: PathBuf,
+  pub names: HashMap<String, NameConfig>,
+  /// when empty every request is allowed
+  pub api_keys: Vec<ApiKey>,
+  /// reverse proxies whose forwarding headers are believed, as CIDRs or single addresses
+  #[serde(deserialize_with = "deserialize_nets")]
+  pub trusted_proxies: Vec<IpNet>,
+  /// header the trusted proxies set, the others are passed through from clients and ignored
+  pub forwarded_header: ForwardedHeader,
+  pub rate_limits: RateLimits,
+  /// request headers kept in the metadata of every record, matched case-insensitively
+  pub metadata_headers: Vec<String>,
+  /// seconds an `Idempotency-Key` is remembered after the request that used it first
+  pub idempotency_window: u64,
+  /// stored bytes allowed for all names together
+  pub max_bytes: Option<u64>,
+  /// records allowed for all names together
+  pub max_records: Option<u64>,
+  /// seconds between runs of the retention task
+  pub retention_interval: u64,
+  /// where records expired with `on_expiry = "archive"` go, defaults to `archive` inside `data_dir`
+  pub archive_dir: Option<PathBuf>,
+  /// seconds requests in flight get to finish after SIGINT or SIGTERM
+  pub shutdown_timeout: u64,
+  /// seconds between a stop signal and refusing new connections, `/readyz` fails meanwhile so load balancers can
+  /// move traffic away
+  pub shutdown_delay: u64,
+  /// bytes that must stay free on the filesystem of `data_dir` for `/readyz` to pass, 0 turns the check off
+  pub min_free_space: u64,
+  pub tls: Option<TlsConfig>,
+  pub metrics: MetricsConfig,
+  pub log: LogConfig,
+  pub webhooks: WebhooksConfig,
+}
+
+/// accepts `10.0.0.0/8` as well as a bare `10.0.0.1`
+fn deserialize_nets<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<IpNet>, D::Error> {
+  let nets = Vec::<String>::deserialize(deserializer)?;
+  nets
+    .iter()
+    .map(|net| {
+      net
+        .parse::<IpNet>()
+        .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| serde::de::Error::custom(format!("invalid CIDR or address {:?}", net)))
+    })
+    .collect()
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Config {
+      data_dir: PathBuf::from("data"),
+      bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+      port: 3000,
+      storage: StorageConfig::default(),
+      format: OutputFormat::default(),
+      max_body_size: 2 * 1024 * 1024,
+      stream_threshold: 1024 * 1024,
+      schemas_dir: PathBuf::from("schemas"),
+      names: HashMap::new(),
+      api_keys: vec![],
+      trusted_proxies: vec![],
+      forwarded_header: ForwardedHeader::default(),
+      rate_limits: RateLimits::default(),
+      metadata_headers: ["content-type", "content-length", "user-agent", "x-request-id"]
+        .map(String::from)
+        .into(),
+      idempotency_window: 24 * 60 * 60,
+      max_bytes: None,
+      max_records: None,
+      retention_interval: 60 * 60,
+      archive_dir: None,
+      shutdown_timeout: 30,
+      shutdown_delay: 0,
+      min_free_space: 100 * 1024 * 1024,
+      tls: None,
+      metrics: MetricsConfig::default(),
+      log: LogConfig::default(),
+      webhooks: WebhooksConfig::default(),
+    }
+  }
+}
+
+/// an exact name, or a prefix ending with `*`; a lone `*` matches every name
+pub fn matches_name(pattern: &str, name: &str) -> bool {
+  match pattern.strip_suffix('*') {
+    Some(prefix) => name.starts_with(prefix),
+    None => name == pattern,
+  }
+}
+
+fn is_valid_pattern(pattern: &str) -> bool {
+  let prefix = pattern.strip_suffix('*').unwrap_or(pattern);
+  (!prefix.is_empty() || pattern == "*") && crate::is_valid_name(prefix)
+}
+
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+  /// reads flags, environment and the optional config file, in that order of precedence, then validates the result
+  pub fn load() -> Result<Config, ConfigError> {
+    let cli = Cli::parse();
+
+    let mut config = match &cli.config {
+      Some(path) => Config::from_file(path)?,
+      None => Config::default(),
+    };
+    if let Some(data_dir) = cli.data_dir {
+      config.data_dir = data_dir;
+    }
+    if let Some(bind) = cli.bind {
+      config.bind = bind;
+    }
+    if let Some(port) = cli.port {
+      config.port = port;
+    }
+    if let Some(backend) = cli.storage {
+      config.storage.backend = backend;
+    }
+    if let Some(format) = cli.log_format {
+      config.log.format = format;
+    }
+
+    config.validate()?;
+    Ok(config)
+  }
+
+  fn from_file(path: &Path) -> Result<Config, ConfigError> {
+    let content = fs::read_to_string(path).map_err(|e| ConfigError(format!("failed to read config {}: {}", path.display(), e)))?;
+    toml::from_str(&content).map_err(|e| ConfigError(format!("invalid config {}: {}", path.display(), e)))
+  }
+
+  /// makes `data_dir` absolute and makes sure it exists and is writable
+  fn validate(&mut self) -> Result<(), ConfigError> {
+    for name in self.names.keys() {
+      if !crate::is_valid_name(name) {
+        return Err(ConfigError(format!("invalid name in config: {:?}", name)));
+      }
+    }
+
+    let limits = &self.rate_limits;
+    let named_limits = self
+      .names
+      .iter()
+      .filter_map(|(name, n)| Some((format!("names.{}.rate_limit", name), n.rate_limit?)));
+    let limits = [
+      ("rate_limits.per_ip", limits.per_ip),
+      ("rate_limits.per_key", limits.per_key),
+      ("rate_limits.per_name", limits.per_name),
+    ]
+    .into_iter()
+    .filter_map(|(what, limit)| Some((what.to_owned(), limit?)))
+    .chain(named_limits);
+    for (what, limit) in limits {
+      if !(limit.rate > 0.0 && limit.rate.is_finite()) || limit.burst == 0 {
+        return Err(ConfigError(format!("{} needs a positive rate and burst", what)));
+      }
+    }
+    if self.rate_limits.max_tracked == 0 {
+      return Err(ConfigError("rate_limits.max_tracked must be positive".to_owned()));
+    }
+
+    let mut seen_keys = std::collections::HashSet::new();
+    for api_key in &self.api_keys {
+      if api_key.key.len() < 16 {
+        return Err(ConfigError(format!("api key {:?} is shorter than 16 characters", api_key.id)));
+      }
+      if !seen_keys.insert(&api_key.key) {
+        return Err(ConfigError(format!("api key {:?} reuses the key of another entry", api_key.id)));
+      }
+      for pattern in &api_key.names {
+        if !is_valid_pattern(pattern) {
+          return Err(ConfigError(format!(
+            "invalid name pattern {:?} for api key {:?}",
+            pattern, api_key.id
+          )));
+        }
+      }
+    }
+
+    if let Some(tls) = &self.tls {
+      if tls.cert.as_os_str().is_empty() || tls.key.as_os_str().is_empty() {
+        return Err(ConfigError("tls needs both cert and key".to_owned()));
+      }
+      if tls.redirect_port == Some(self.port) {
+        return Err(ConfigError("tls.redirect_port must differ from port".to_owned()));
+      }
+    }
+
+    if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log.filter) {
+      return Err(ConfigError(format!("invalid log.filter {:?}: {}", self.log.filter, e)));
+    }
+
+    if let Some(pattern) = self.metrics.names.iter().find(|pattern| !is_valid_pattern(pattern)) {
+      return Err(ConfigError(format!("invalid name pattern {:?} in metrics.names", pattern)));
+    }
+
+    let webhooks = &self.webhooks;
+    let mut seen_ids = std::collections::HashSet::new();
+    for endpoint in &webhooks.endpoints {
+      if endpoint.id.is_empty() || !crate::is_valid_name(&endpoint.id) {
+        return Err(ConfigError(format!("invalid webhook id {:?}", endpoint.id)));
+      }
+      if !seen_ids.insert(&endpoint.id) {
+        return Err(ConfigError(format!("webhook id {:?} is used twice", endpoint.id)));
+      }
+      match reqwest::Url::parse(&endpoint.url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+        _ => return Err(ConfigError(format!("webhook {:?} needs an http or https url", endpoint.id))),
+      }
+      if endpoint.secret.len() < 16 {
+        return Err(ConfigError(format!(
+          "secret of webhook {:?} is shorter than 16 characters",
+          endpoint.id
+        )));
+      }
+      if let Some(pattern) = endpoint.names.iter().find(|pattern| !is_valid_pattern(pattern)) {
+        return Err(ConfigError(format!(
+          "invalid name pattern {:?} for webhook {:?}",
+          pattern, endpoint.id
+        )));
+      }
+    }
+    if webhooks.max_attempts == 0 || webhooks.initial_backoff == 0 || webhooks.timeout == 0 || webhooks.concurrency == 0 {
+      return Err(ConfigError(
+        "webhooks.max_attempts, initial_backoff, timeout and concurrency must be positive".to_owned(),
+      ));
+    }
+
+    for header in &mut self.metadata_headers {
+      if axum::http::HeaderName::from_bytes(header.as_bytes()).is_err() {
+        return Err(ConfigError(format!("invalid header name in metadata_headers: {:?}", header)));
+      }
+      header.make_ascii_lowercase();
+    }
+
+    let dir = &self.data_dir;
+    fs::create_dir_all(dir).map_err(|e| ConfigError(format!("failed to create data directory {}: {}", dir.display(), e)))?;
+    self.data_dir =
+      fs::canonicalize(dir).map_err(|e| ConfigError(format!("failed to resolve data directory {}: {}", dir.display(), e)))?;
+
+    crate::durable::probe_writable(&self.data_dir)
+      .map_err(|e| ConfigError(format!("data directory {} is not writable: {}", self.data_dir.display(), e)))?;
+
+    Ok(())
+  }
+
+  pub fn listen_addr(&self) -> SocketAddr {
+    SocketAddr::new(self.bind, self.port)
+  }
+
+  pub fn max_body_size(&self, name: &str) -> u64 {
+    self.names.get(name).and_then(|n| n.max_body_size).unwrap_or(self.max_body_size)
+  }
+
+  pub fn format(&self, name: &str) -> OutputFormat {
+    self.names.get(name).and_then(|n| n.format).unwrap_or(self.format)
+  }
+
+  pub fn dedup_window(&self, name: &str) -> Option<u64> {
+    self.names.get(name).and_then(|n| n.dedup_window)
+  }
+
+  pub fn archive_dir(&self) -> PathBuf {
+    self.archive_dir.clone().unwrap_or_else(|| self.data_dir.join("archive"))
+  }
+
+  pub fn name_rate_limit(&self, name: &str) -> Option<RateLimit> {
+    self.names.get(name).and_then(|n| n.rate_limit).or(self.rate_limits.per_name)
+  }
+
+  pub fn name(&self, name: &str) -> NameConfig {
+    self.names.get(name).cloned().unwrap_or_default()
+  }
+}
@@ -0,0 +1,340 @@
+//! content-hash deduplication for names with a `dedup_window`: a JSON payload identical to one stored within the
+//! window is answered with the existing record instead of being stored again
+
+use crate::{
+  config::Config,
+  error::AppError,
+  storage::{sha256_hex, BodyKind, RecordMeta, Storage},
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde_json::Value;
+use std::{
+  collections::HashMap,
+  io,
+  sync::{Arc, Mutex},
+};
+
+/// expired entries are swept once the index grows past this, and past twice its size after the last sweep
+const MIN_SWEEP_LEN: usize = 1024;
+
+struct Entry {
+  meta: RecordMeta,
+  expires_at: DateTime<Utc>,
+}
+
+struct Index {
+  /// by name and canonical hash
+  entries: HashMap<(String, String), Entry>,
+  /// held while a payload is stored, by name and canonical hash
+  saving: HashMap<(String, String), Arc<Mutex<()>>>,
+  sweep_at: usize,
+}
+
+pub struct Dedup {
+  index: Mutex<Index>,
+}
+
+/// SHA-256 of the payload encoded without whitespace and with sorted keys, so formatting and key order do not matter.
+/// `None` when the body is not JSON
+pub fn canonical_hash(body: &[u8]) -> Option<String> {
+  // serde_json keeps object keys sorted
+  let value: Value = serde_json::from_slice(body).ok()?;
+  Some(sha256_hex(&serde_json::to_vec(&value).ok()?))
+}
+
+/// windows too long to represent never expire
+fn expiry(received_at: DateTime<Utc>, seconds: u64) -> DateTime<Utc> {
+  i64::try_from(seconds)
+    .ok()
+    .and_then(TimeDelta::try_seconds)
+    .and_then(|window| received_at.checked_add_signed(window))
+    .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+impl Dedup {
+  /// indexes the stored JSON records of every name with a window that are still within it
+  pub fn rebuild(storage: &dyn Storage, config: &Config) -> io::Result<Dedup> {
+    let now = Utc::now();
+    let mut entries = HashMap::new();
+    for (name, name_config) in &config.names {
+      let Some(seconds) = name_config.dedup_window else {
+        continue;
+      };
+      for meta in storage.list(name)? {
+        let Some(received_at) = meta.received_at.as_deref().and_then(|t| DateTime::parse_from_rfc3339(t).ok()) else {
+          continue;
+        };
+        let expires_at = expiry(received_at.to_utc(), seconds);
+        if meta.kind != BodyKind::Json || expires_at <= now {
+          continue;
+        }
+        let Some((_, body)) = storage.get(name, &meta.id)? else {
+          continue;
+        };
+        let Some(hash) = canonical_hash(&body) else {
+          continue;
+        };
+        // ids order by address before time within a day, so of identical records the one expiring last is kept
+        let key = (name.clone(), hash);
+        if entries.get(&key).is_none_or(|kept: &Entry| kept.expires_at < expires_at) {
+          entries.insert(key, Entry { meta, expires_at });
+        }
+      }
+    }
+
+    Ok(Dedup {
+      index: Mutex::new(Index {
+        sweep_at: (entries.len() * 2).max(MIN_SWEEP_LEN),
+        entries,
+        saving: HashMap::new(),
+      }),
+    })
+  }
+
+  pub fn len(&self) -> usize {
+    self.index().entries.len()
+  }
+
+  fn index(&self) -> std::sync::MutexGuard<'_, Index> {
+    self.index.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  /// calls `store` unless a payload of `name` with the same hash was stored within `seconds` before `received_at`,
+  /// returns the new or the existing record and whether it was an existing one.
+  /// identical payloads arriving together wait for each other, so they are stored once, other payloads are stored meanwhile
+  pub fn store_once(
+    &self,
+    name: &str,
+    hash: String,
+    seconds: u64,
+    received_at: DateTime<Utc>,
+    store: impl FnOnce() -> Result<RecordMeta, AppError>,
+  ) -> Result<(RecordMeta, bool), AppError> {
+    let key = (name.to_owned(), hash);
+    let saving = self.index().saving.entry(key.clone()).or_default().clone();
+    let result = {
+      let _saving = saving.lock().unwrap_or_else(|e| e.into_inner());
+      self.store_locked(&key, seconds, received_at, store)
+    };
+
+    let mut index = self.index();
+    // the index and this call hold the last references, nobody else waits for the payload
+    if Arc::strong_count(&saving) == 2 {
+      index.saving.remove(&key);
+    }
+    result
+  }
+
+  fn store_locked(
+    &self,
+    key: &(String, String),
+    seconds: u64,
+    received_at: DateTime<Utc>,
+    store: impl FnOnce() -> Result<RecordMeta, AppError>,
+  ) -> Result<(RecordMeta, bool), AppError> {
+    let existing = self
+      .index()
+      .entries
+      .get(key)
+      .filter(|entry| entry.expires_at > received_at)
+      .map(|entry| entry.meta.clone());
+    if let Some(meta) = existing {
+      return Ok((meta, true));
+    }
+
+    let meta = store()?;
+    let mut index = self.index();
+    index.entries.insert(
+      key.clone(),
+      Entry {
+        meta: meta.clone(),
+        expires_at: expiry(received_at, seconds),
+      },
+    );
+    if index.entries.len() > index.sweep_at {
+      index.entries.retain(|_, entry| entry.expires_at > received_at);
+      index.sweep_at = (index.entries.len() * 2).max(MIN_SWEEP_LEN);
+    }
+    Ok((meta, false))
+  }
+
+  /// drops a deleted record, so an identical payload is stored again
+  pub fn forget(&self, name: &str, id: &str) {
+    self
+      .index()
+      .entries
+      .retain(|(entry_name, _), entry| entry_name != name || entry.meta.id != id);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    config::NameConfig,
+    storage::{FsStorage, NewRecord},
+  };
+
+  fn dedup() -> Dedup {
+    Dedup {
+      index: Mutex::new(Index {
+        entries: HashMap::new(),
+        saving: HashMap::new(),
+        sweep_at: MIN_SWEEP_LEN,
+      }),
+    }
+  }
+
+  fn meta(id: &str) -> RecordMeta {
+    RecordMeta {
+      id: id.to_owned(),
+      filename: None,
+      date: String::new(),
+      addr: String::new(),
+      received_at: None,
+      kind: BodyKind::Json,
+      size: 0,
+    }
+  }
+
+  #[test]
+  fn hashes_ignore_whitespace_and_key_order() {
+    let hash = canonical_hash(br#"{"a":1,"b":[1,2]}"#).unwrap();
+    assert_eq!(canonical_hash(b"{ \"b\" : [1, 2],\n \"a\": 1 }").as_ref(), Some(&hash));
+    assert_ne!(canonical_hash(br#"{"a":1,"b":[2,1]}"#).as_ref(), Some(&hash));
+    assert_eq!(canonical_hash(b"not json"), None);
+  }
+
+  #[test]
+  fn identical_payloads_are_stored_once_within_the_window() {
+    let dedup = dedup();
+    let start = Utc::now();
+    let store = |id: &str| {
+      let meta = meta(id);
+      move || Ok(meta)
+    };
+
+    let (first, existing) = dedup.store_once("lab", "h".to_owned(), 60, start, store("1")).unwrap();
+    assert_eq!((first.id.as_str(), existing), ("1", false));
+    let (again, existing) = dedup
+      .store_once("lab", "h".to_owned(), 60, start + TimeDelta::seconds(59), store("2"))
+      .unwrap();
+    assert_eq!((again.id.as_str(), existing), ("1", true));
+    // names are deduplicated apart
+    let (other, existing) = dedup.store_once("other", "h".to_owned(), 60, start, store("3")).unwrap();
+    assert_eq!((other.id.as_str(), existing), ("3", false));
+
+    let (expired, existing) = dedup
+      .store_once("lab", "h".to_owned(), 60, start + TimeDelta::seconds(60), store("4"))
+      .unwrap();
+    assert_eq!((expired.id.as_str(), existing), ("4", false));
+
+    // a deleted record is stored again
+    dedup.forget("lab", "4");
+    let (stored, existing) = dedup
+      .store_once("lab", "h".to_owned(), 60, start + TimeDelta::seconds(61), store("5"))
+      .unwrap();
+    assert_eq!((stored.id.as_str(), existing), ("5", false));
+    assert_eq!(dedup.len(), 2);
+  }
+
+  #[test]
+  fn failed_saves_are_not_indexed() {
+    let dedup = dedup();
+    let failed = dedup.store_once("lab", "h".to_owned(), 60, Utc::now(), || {
+      Err(AppError::NotFound("Record".to_owned()))
+    });
+    assert!(failed.is_err());
+    assert_eq!(dedup.len(), 0);
+  }
+
+  #[test]
+  fn only_identical_payloads_wait_for_each_other() {
+    let dedup = dedup();
+    let now = Utc::now();
+    // a save of another payload goes on while the first one is still being stored
+    let (first, existing) = dedup
+      .store_once("lab", "a".to_owned(), 60, now, || {
+        let (other, existing) = dedup.store_once("lab", "b".to_owned(), 60, now, || Ok(meta("2")))?;
+        assert_eq!((other.id.as_str(), existing), ("2", false));
+        Ok(meta("1"))
+      })
+      .unwrap();
+    assert_eq!((first.id.as_str(), existing), ("1", false));
+    assert!(dedup.index().saving.is_empty());
+
+    // identical ones arriving together are stored once
+    let stored = std::sync::atomic::AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+      for _ in 0..8 {
+        scope.spawn(|| {
+          dedup
+            .store_once("lab", "c".to_owned(), 60, now, || {
+              stored.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+              std::thread::sleep(std::time::Duration::from_millis(10));
+              Ok(meta("3"))
+            })
+            .unwrap()
+        });
+      }
+    });
+    assert_eq!(stored.into_inner(), 1);
+    assert!(dedup.index().saving.is_empty());
+  }
+
+  #[test]
+  fn windows_too_long_never_expire() {
+    assert_eq!(expiry(Utc::now(), u64::MAX), DateTime::<Utc>::MAX_UTC);
+  }
+
+  #[test]
+  fn the_index_is_rebuilt_from_recent_records() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = FsStorage::open(dir.path(), Default::default()).unwrap();
+    let mut config = Config::default();
+    config.names.insert(
+      "lab".to_owned(),
+      NameConfig {
+        dedup_window: Some(3600),
+        ..NameConfig::default()
+      },
+    );
+    let record = |received_at, body: &[u8]| NewRecord::test("lab", "127.0.0.1", received_at, body);
+    let now = Utc::now();
+    let recent = storage.put(&record(now, b"{\"a\": 1}")).unwrap();
+    storage.put(&record(now - TimeDelta::hours(2), b"{\"b\": 1}")).unwrap();
+
+    let dedup = Dedup::rebuild(&storage, &config).unwrap();
+    assert_eq!(dedup.len(), 1);
+    let hash = canonical_hash(br#"{"a":1}"#).unwrap();
+    let (meta, existing) = dedup.store_once("lab", hash, 3600, now, || unreachable!()).unwrap();
+    assert_eq!((meta.id, existing), (recent.id, true));
+  }
+
+  #[test]
+  fn rebuilding_keeps_the_identical_record_expiring_last() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = FsStorage::open(dir.path(), Default::default()).unwrap();
+    let mut config = Config::default();
+    let window = 100 * 365 * 86400;
+    config.names.insert(
+      "lab".to_owned(),
+      NameConfig {
+        dedup_window: Some(window),
+        ..NameConfig::default()
+      },
+    );
+    let record = |addr, received_at: &str| NewRecord::test("lab", addr, received_at.parse().unwrap(), br#"{"a":1}"#);
+    let later = storage.put(&record("10.0.0.1", "2024-01-01T09:00:00Z")).unwrap();
+    let earlier = storage.put(&record("10.0.0.9", "2024-01-01T08:00:00Z")).unwrap();
+    // the earlier record is listed last
+    assert!(earlier.id > later.id);
+
+    let dedup = Dedup::rebuild(&storage, &config).unwrap();
+    let hash = canonical_hash(br#"{"a":1}"#).unwrap();
+    // past the window of the earlier record, within the one of the later
+    let received_at = expiry("2024-01-01T08:30:00Z".parse().unwrap(), window);
+    let (meta, existing) = dedup.store_once("lab", hash, window, received_at, || unreachable!()).unwrap();
+    assert_eq!((meta.id, existing), (later.id, true));
+  }
+}
@@ -0,0 +1,290 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+  fs::{self, File, OpenOptions},
+  io::{self, BufRead, BufReader, Read, Write},
+  path::{Path, PathBuf},
+  sync::atomic::{AtomicU64, Ordering},
+};
+
+const TEMP_PREFIX: &str = ".";
+const TEMP_SUFFIX: &str = ".tmp";
+/// a `JsonLog` is rewritten with only its live entries once it holds this many more lines than there are live entries
+const COMPACT_SLACK: usize = 1000;
+
+/// unix seconds, what entries of a `JsonLog` are stamped with
+pub fn now() -> i64 {
+  chrono::Utc::now().timestamp()
+}
+
+/// copies `reader` to `dir/filename` so that readers of the directory see either nothing or the whole file:
+/// the bytes go to a hidden temp file which is fsynced, renamed into place, then the directory is fsynced.
+/// an existing file is replaced. returns the number of bytes written, when `reader` fails nothing is left behind
+pub fn write_atomic(dir: &Path, filename: &str, reader: &mut dyn Read) -> io::Result<u64> {
+  let (temp, written) = TempFile::write(dir, reader)?;
+  temp.persist(filename)?;
+  Ok(written)
+}
+
+/// like `write_atomic`, but fails with `AlreadyExists` instead of replacing a file, also when another process
+/// sharing the directory creates `filename` at the same time
+pub fn write_new(dir: &Path, filename: &str, reader: &mut dyn Read) -> io::Result<u64> {
+  let (temp, written) = TempFile::write(dir, reader)?;
+  temp.link(filename)?;
+  Ok(written)
+}
+
+/// a fsynced hidden file in `dir` that is not visible under a real name yet, removed when dropped
+pub struct TempFile {
+  dir: PathBuf,
+  path: PathBuf,
+}
+
+impl TempFile {
+  /// copies `reader` to a new temp file, returns it with the number of bytes written
+  pub fn write(dir: &Path, reader: &mut dyn Read) -> io::Result<(TempFile, u64)> {
+    static SEQ: AtomicU64 = AtomicU64::new(0);
+    let filename = format!(
+      "{}{}-{}{}",
+      TEMP_PREFIX,
+      std::process::id(),
+      SEQ.fetch_add(1, Ordering::Relaxed),
+      TEMP_SUFFIX
+    );
+    let temp = TempFile {
+      dir: dir.to_owned(),
+      path: dir.join(filename),
+    };
+
+    let mut file = OpenOptions::new().write(true).create_new(true).open(&temp.path)?;
+    let written = io::copy(reader, &mut file)?;
+    file.flush()?;
+    file.sync_all()?;
+    Ok((temp, written))
+  }
+
+  /// makes the content visible as `filename` too, failing with `AlreadyExists` instead of replacing a file.
+  /// unlike a rename the link is refused atomically by the filesystem, so it can be retried under another name
+  pub fn link(&self, filename: &str) -> io::Result<()> {
+    fs::hard_link(&self.path, self.dir.join(filename))?;
+    sync_dir(&self.dir)
+  }
+
+  /// renames the file to `filename`, replacing any file of that name
+  pub fn persist(self, filename: &str) -> io::Result<()> {
+    fs::rename(&self.path, self.dir.join(filename))?;
+    sync_dir(&self.dir)
+  }
+}
+
+impl Drop for TempFile {
+  fn drop(&mut self) {
+    // already renamed, or linked and no longer needed, or the record was not saved anyway
+    let _ = fs::remove_file(&self.path);
+  }
+}
+
+/// an append-only log of JSON lines kept next to the records, each line the new state of one entry. its owner keeps
+/// the live entries in memory, replayed from the log at startup, and appends every change before acting on it
+pub struct JsonLog {
+  dir: PathBuf,
+  filename: &'static str,
+  file: File,
+  /// lines in the log, some of them superseded
+  lines: usize,
+}
+
+impl JsonLog {
+  /// replays every line of `dir/filename` that parses as a `T`, oldest first. a line cut short by a crash is skipped
+  /// like any other that does not parse, so the owner should `rewrite` the log before appending to it
+  pub fn open<T: DeserializeOwned>(dir: &Path, filename: &'static str, mut replay: impl FnMut(T)) -> io::Result<JsonLog> {
+    let path = dir.join(filename);
+    let mut lines = 0;
+    match File::open(&path) {
+      Ok(file) => {
+        for line in BufReader::new(file).lines() {
+          lines += 1;
+          if let Ok(entry) = serde_json::from_str(&line?) {
+            replay(entry);
+          }
+        }
+      }
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+      Err(e) => return Err(e),
+    }
+
+    Ok(JsonLog {
+      dir: dir.to_owned(),
+      filename,
+      file: OpenOptions::new().create(true).append(true).open(&path)?,
+      lines,
+    })
+  }
+
+  /// appends `changed` and fsyncs them
+  pub fn append<T: Serialize>(&mut self, changed: &[T]) -> io::Result<()> {
+    let mut content = vec![];
+    for entry in changed {
+      serde_json::to_writer(&mut content, entry)?;
+      content.push(b'\n');
+    }
+    self.file.write_all(&content)?;
+    self.file.sync_data()?;
+    self.lines += changed.len();
+    Ok(())
+  }
+
+  /// whether the log holds enough superseded lines next to `live` entries to be worth a `rewrite`
+  pub fn needs_compaction(&self, live: usize) -> bool {
+    self.lines > live + COMPACT_SLACK
+  }
+
+  /// replaces the log with one line for each of `live`
+  pub fn rewrite<'a, T: Serialize + 'a>(&mut self, live: impl IntoIterator<Item = &'a T>) -> io::Result<()> {
+    let mut content = vec![];
+    let mut lines = 0;
+    for entry in live {
+      serde_json::to_writer(&mut content, entry)?;
+      content.push(b'\n');
+      lines += 1;
+    }
+    write_atomic(&self.dir, self.filename, &mut &content[..])?;
+    self.file = OpenOptions::new().append(true).open(self.dir.join(self.filename))?;
+    self.lines = lines;
+    Ok(())
+  }
+}
+
+/// fsyncs a directory so a rename inside it survives a crash
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+  File::open(dir)?.sync_all()
+}
+
+/// creates and removes an empty file in `dir`, named like a temp file so a crash in between leaves nothing for long
+pub fn probe_writable(dir: &Path) -> io::Result<()> {
+  let probe = dir.join(format!("{}write-probe-{}{}", TEMP_PREFIX, std::process::id(), TEMP_SUFFIX));
+  fs::write(&probe, b"")?;
+  fs::remove_file(&probe)
+}
+
+/// bytes available to unprivileged processes on the filesystem holding `dir`
+#[cfg(unix)]
+pub fn available_space(dir: &Path) -> io::Result<u64> {
+  use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+  let path = CString::new(dir.as_os_str().as_bytes()).map_err(io::Error::other)?;
+  // SAFETY: `statvfs` only writes to the struct, which is plain old data
+  let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+  if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+    return Err(io::Error::last_os_error());
+  }
+  // the field types differ between platforms
+  #[allow(clippy::unnecessary_cast)]
+  Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_dir: &Path) -> io::Result<u64> {
+  Err(io::Error::new(io::ErrorKind::Unsupported, "free space is only known on unix"))
+}
+
+/// removes temp files left by `write_atomic` when a previous run died mid-write, returns how many were removed
+pub fn sweep_temp_files(dir: &Path) -> io::Result<usize> {
+  let entries = match fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+    Err(e) => return Err(e),
+  };
+
+  let mut removed = 0;
+  for entry in entries {
+    let entry = entry?;
+    let filename = entry.file_name();
+    let filename = filename.to_string_lossy();
+    if filename.starts_with(TEMP_PREFIX) && filename.ends_with(TEMP_SUFFIX) && entry.file_type()?.is_file() {
+      fs::remove_file(entry.path())?;
+      removed += 1;
+    }
+  }
+  Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn names(dir: &Path) -> Vec<String> {
+    let mut names: Vec<_> = fs::read_dir(dir)
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+      .collect();
+    names.sort();
+    names
+  }
+
+  #[test]
+  fn write_new_does_not_replace() {
+    let dir = tempfile::tempdir().unwrap();
+    assert_eq!(write_new(dir.path(), "a.json", &mut &b"first"[..]).unwrap(), 5);
+    let e = write_new(dir.path(), "a.json", &mut &b"second"[..]).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(fs::read(dir.path().join("a.json")).unwrap(), b"first");
+    assert_eq!(names(dir.path()), ["a.json"]);
+  }
+
+  #[test]
+  fn write_atomic_replaces() {
+    let dir = tempfile::tempdir().unwrap();
+    write_atomic(dir.path(), "log.jsonl", &mut &b"first"[..]).unwrap();
+    write_atomic(dir.path(), "log.jsonl", &mut &b"second"[..]).unwrap();
+    assert_eq!(fs::read(dir.path().join("log.jsonl")).unwrap(), b"second");
+    assert_eq!(names(dir.path()), ["log.jsonl"]);
+  }
+
+  #[test]
+  fn temp_file_links_under_a_free_name() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("taken"), b"theirs").unwrap();
+    let (temp, _) = TempFile::write(dir.path(), &mut &b"ours"[..]).unwrap();
+    assert_eq!(temp.link("taken").unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    temp.link("free").unwrap();
+    drop(temp);
+    assert_eq!(fs::read(dir.path().join("taken")).unwrap(), b"theirs");
+    assert_eq!(fs::read(dir.path().join("free")).unwrap(), b"ours");
+    assert_eq!(names(dir.path()), ["free", "taken"]);
+  }
+
+  #[test]
+  fn json_logs_replay_appended_lines_and_compact() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut log = JsonLog::open(dir.path(), "log.jsonl", |_: u32| unreachable!()).unwrap();
+    log.append(&[1, 2]).unwrap();
+    assert!(!log.needs_compaction(0));
+    log.append(&vec![3; COMPACT_SLACK]).unwrap();
+    assert!(log.needs_compaction(1));
+    log.rewrite(&[3]).unwrap();
+    assert!(!log.needs_compaction(1));
+    log.append(&[4]).unwrap();
+    drop(log);
+
+    // a line cut short by a crash is skipped
+    let path = dir.path().join("log.jsonl");
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(b"{\"torn").unwrap();
+    let mut replayed = vec![];
+    JsonLog::open(dir.path(), "log.jsonl", |entry: u32| replayed.push(entry)).unwrap();
+    assert_eq!(replayed, [3, 4]);
+  }
+
+  #[test]
+  fn failed_reader_leaves_nothing() {
+    struct Failing;
+    impl Read for Failing {
+      fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::other("connection reset"))
+      }
+    }
+    let dir = tempfile::tempdir().unwrap();
+    assert!(write_new(dir.path(), "a.json", &mut Failing).is_err());
+    assert!(names(dir.path()).is_empty());
+  }
+}
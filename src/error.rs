@@ -0,0 +1,152 @@
+use crate::schemas::Violation;
+use axum::{
+  http::{header, StatusCode},
+  response::{IntoResponse, Response},
+  Json,
+};
+use serde_json::json;
+use std::{fmt, io};
+
+/// `code` of an error response, left in the response extensions for the metrics middleware
+#[derive(Clone, Copy, Debug)]
+pub struct ErrorCode(pub &'static str);
+
+/// errors returned by handlers, rendered as `{"error": {"code", "message"}}`
+#[derive(Debug)]
+pub enum AppError {
+  /// name or record id outside of `[\w\d\-_]+`
+  InvalidName(String),
+  /// request body could not be extracted, keeps the status picked by axum
+  InvalidBody {
+    status: StatusCode,
+    message: String,
+  },
+  /// a request header has a value that cannot be used
+  InvalidHeader(String),
+  NotFound(String),
+  /// the request clashes with an earlier one
+  Conflict(String),
+  /// body exceeds the limit, in bytes, configured for the name
+  PayloadTooLarge(u64),
+  /// saving would go past `max_bytes` or `max_records`, tells which one
+  QuotaExceeded(String),
+  /// payload does not match the JSON Schema of its name
+  SchemaViolation(Vec<Violation>),
+  /// missing or unknown api key
+  Unauthorized,
+  /// api key, by id, lacks the permission or the name
+  Forbidden(String),
+  /// a rate limit was exceeded, carries the seconds until the next request fits
+  RateLimited(u64),
+  /// file system failure, `context` tells what was being done
+  Io {
+    context: String,
+    source: io::Error,
+  },
+}
+
+impl AppError {
+  /// wraps an `io::Error` with context, to be used as `.map_err(AppError::io("reading record"))`
+  pub fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> Self {
+    let context = context.into();
+    move |source| AppError::Io { context, source }
+  }
+
+  pub fn status(&self) -> StatusCode {
+    match self {
+      AppError::InvalidName(_) => StatusCode::BAD_REQUEST,
+      AppError::InvalidBody { status, .. } => *status,
+      AppError::InvalidHeader(_) => StatusCode::BAD_REQUEST,
+      AppError::NotFound(_) => StatusCode::NOT_FOUND,
+      AppError::Conflict(_) => StatusCode::CONFLICT,
+      AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+      AppError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+      AppError::SchemaViolation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+      AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+      AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+      AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+      AppError::Io { source, .. } if is_storage_full(source) => StatusCode::INSUFFICIENT_STORAGE,
+      AppError::Io { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+  }
+
+  pub fn code(&self) -> &'static str {
+    match self {
+      AppError::InvalidName(_) => "invalid_name",
+      AppError::InvalidBody { .. } => "invalid_body",
+      AppError::InvalidHeader(_) => "invalid_header",
+      AppError::NotFound(_) => "not_found",
+      AppError::Conflict(_) => "conflict",
+      AppError::PayloadTooLarge(_) => "payload_too_large",
+      AppError::QuotaExceeded(_) => "quota_exceeded",
+      AppError::SchemaViolation(_) => "schema_violation",
+      AppError::Unauthorized => "unauthorized",
+      AppError::Forbidden(_) => "forbidden",
+      AppError::RateLimited(_) => "rate_limited",
+      AppError::Io { source, .. } if is_storage_full(source) => "insufficient_storage",
+      AppError::Io { .. } => "io_error",
+    }
+  }
+}
+
+/// ENOSPC and EDQUOT, both mean there is no room left for the record
+fn is_storage_full(e: &io::Error) -> bool {
+  matches!(e.kind(), io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded)
+}
+
+impl fmt::Display for AppError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      AppError::InvalidName(name) => write!(f, "Invalid name: {:?}", name),
+      AppError::InvalidBody { message, .. } => write!(f, "Invalid body: {}", message),
+      AppError::InvalidHeader(message) => write!(f, "Invalid header: {}", message),
+      AppError::NotFound(what) => write!(f, "{} not found", what),
+      AppError::Conflict(message) => write!(f, "Conflict: {}", message),
+      AppError::PayloadTooLarge(limit) => write!(f, "Body is larger than the limit of {} bytes", limit),
+      AppError::QuotaExceeded(quota) => write!(f, "Saving this would exceed {}", quota),
+      AppError::SchemaViolation(violations) => write!(f, "Payload does not match the schema ({} violations)", violations.len()),
+      AppError::Unauthorized => write!(f, "Missing or unknown api key, pass one as `Authorization: Bearer <key>`"),
+      AppError::Forbidden(key_id) => write!(f, "Api key {:?} is not allowed to do this", key_id),
+      AppError::RateLimited(retry_after) => write!(f, "Too many requests, retry in {} seconds", retry_after),
+      AppError::Io { context, source } => write!(f, "Failed {}: {}", context, source),
+    }
+  }
+}
+
+impl std::error::Error for AppError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      AppError::Io { source, .. } => Some(source),
+      _ => None,
+    }
+  }
+}
+
+impl IntoResponse for AppError {
+  fn into_response(self) -> Response {
+    let status = self.status();
+    if status.is_server_error() {
+      tracing::error!(code = self.code(), "{}", self);
+    }
+    let mut body = json!({ "error": { "code": self.code(), "message": self.to_string() } });
+    if let AppError::SchemaViolation(violations) = &self {
+      body["error"]["violations"] = json!(violations);
+    }
+    let mut response = (status, Json(body)).into_response();
+    response.extensions_mut().insert(ErrorCode(self.code()));
+    match self {
+      AppError::Unauthorized => {
+        response
+          .headers_mut()
+          .insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+      }
+      AppError::RateLimited(retry_after) => {
+        response
+          .headers_mut()
+          .insert(header::RETRY_AFTER, header::HeaderValue::from(retry_after));
+      }
+      _ => {}
+    }
+    response
+  }
+}
@@ -0,0 +1,174 @@
+use crate::error::AppError;
+use axum::http::{header, HeaderMap, StatusCode};
+use serde_json::{Map, Value};
+
+/// request body formats accepted by `save_data`, picked from `Content-Type`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+  /// `application/json` or any `application/*+json`
+  Json,
+  /// `application/x-ndjson` or `application/jsonl`, every line is saved as its own record
+  NdJson,
+  /// `text/csv`, rows become objects keyed by the header row
+  Csv,
+  /// `application/x-www-form-urlencoded`, becomes an object, repeated fields become arrays
+  Form,
+  /// `application/octet-stream`, stored as is next to a metadata sidecar
+  Binary,
+}
+
+impl InputFormat {
+  pub fn from_headers(headers: &HeaderMap) -> Result<InputFormat, AppError> {
+    let mime = headers
+      .get(header::CONTENT_TYPE)
+      .and_then(|value| value.to_str().ok())
+      .map(|value| value.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+      .unwrap_or_default();
+
+    match mime.as_str() {
+      "application/json" => Ok(InputFormat::Json),
+      mime if mime.starts_with("application/") && mime.ends_with("+json") => Ok(InputFormat::Json),
+      "application/x-ndjson" | "application/jsonl" | "application/jsonlines" => Ok(InputFormat::NdJson),
+      "text/csv" => Ok(InputFormat::Csv),
+      "application/x-www-form-urlencoded" => Ok(InputFormat::Form),
+      "application/octet-stream" => Ok(InputFormat::Binary),
+      _ => Err(AppError::InvalidBody {
+        status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        message: format!(
+          "Unsupported `Content-Type: {}`, expected application/json, application/x-ndjson, text/csv, \
+           application/x-www-form-urlencoded or application/octet-stream",
+          mime
+        ),
+      }),
+    }
+  }
+}
+
+fn bad_request(message: String) -> AppError {
+  AppError::InvalidBody {
+    status: StatusCode::BAD_REQUEST,
+    message,
+  }
+}
+
+/// non-empty lines of an NDJSON body with their 1-based line numbers, `\r\n` endings are accepted
+pub fn ndjson_lines(bytes: &[u8]) -> impl Iterator<Item = (usize, &[u8])> {
+  bytes
+    .split(|&b| b == b'\n')
+    .enumerate()
+    .map(|(i, line)| (i + 1, line.strip_suffix(b"\r").unwrap_or(line)))
+    .filter(|(_, line)| !line.iter().all(u8::is_ascii_whitespace))
+}
+
+/// an array with one object per row, keyed by the header row, all values are strings
+pub fn csv_to_json(bytes: &[u8]) -> Result<Value, AppError> {
+  let mut reader = csv::Reader::from_reader(bytes);
+  let headers = reader
+    .headers()
+    .map_err(|e| bad_request(format!("Failed to parse CSV header: {}", e)))?
+    .clone();
+
+  let mut rows = vec![];
+  for row in reader.records() {
+    let row = row.map_err(|e| bad_request(format!("Failed to parse CSV: {}", e)))?;
+    let object: Map<String, Value> = headers
+      .iter()
+      .zip(row.iter())
+      .map(|(key, value)| (key.to_owned(), Value::from(value)))
+      .collect();
+    rows.push(Value::Object(object));
+  }
+  Ok(Value::Array(rows))
+}
+
+/// an object of the form fields, a field given more than once becomes an array of its values
+pub fn form_to_json(bytes: &[u8]) -> Result<Value, AppError> {
+  let fields: Vec<(String, String)> =
+    serde_urlencoded::from_bytes(bytes).map_err(|e| bad_request(format!("Failed to parse form: {}", e)))?;
+
+  let mut object = Map::new();
+  for (key, value) in fields {
+    match object.get_mut(&key) {
+      None => {
+        object.insert(key, Value::from(value));
+      }
+      Some(Value::Array(values)) => values.push(Value::from(value)),
+      Some(existing) => *existing = Value::Array(vec![existing.take(), Value::from(value)]),
+    }
+  }
+  Ok(Value::Object(object))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use axum::http::HeaderValue;
+  use serde_json::json;
+
+  fn format(content_type: Option<&str>) -> Result<InputFormat, StatusCode> {
+    let mut headers = HeaderMap::new();
+    if let Some(content_type) = content_type {
+      headers.insert(header::CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap());
+    }
+    InputFormat::from_headers(&headers).map_err(|e| match e {
+      AppError::InvalidBody { status, .. } => status,
+      _ => panic!("unexpected error"),
+    })
+  }
+
+  fn status(result: Result<Value, AppError>) -> StatusCode {
+    match result {
+      Err(AppError::InvalidBody { status, .. }) => status,
+      _ => panic!("body accepted"),
+    }
+  }
+
+  #[test]
+  fn formats_are_picked_from_the_content_type() {
+    assert_eq!(format(Some("application/json")), Ok(InputFormat::Json));
+    assert_eq!(format(Some("application/ld+json")), Ok(InputFormat::Json));
+    assert_eq!(format(Some("application/vnd.api+json; charset=utf-8")), Ok(InputFormat::Json));
+    assert_eq!(format(Some("Application/JSON; Charset=UTF-8")), Ok(InputFormat::Json));
+    assert_eq!(format(Some("application/x-ndjson")), Ok(InputFormat::NdJson));
+    assert_eq!(format(Some("application/jsonl")), Ok(InputFormat::NdJson));
+    assert_eq!(format(Some("TEXT/CSV;charset=utf-8")), Ok(InputFormat::Csv));
+    assert_eq!(format(Some("application/x-www-form-urlencoded")), Ok(InputFormat::Form));
+    assert_eq!(format(Some(" application/octet-stream ")), Ok(InputFormat::Binary));
+
+    for unsupported in [None, Some(""), Some("text/plain"), Some("text/x+json"), Some("application/jsonx")] {
+      assert_eq!(format(unsupported), Err(StatusCode::UNSUPPORTED_MEDIA_TYPE), "{:?}", unsupported);
+    }
+  }
+
+  #[test]
+  fn ndjson_lines_keep_their_numbers() {
+    let body = b"{\"a\":1}\r\n\n  \t\r\n{\"b\":2}\n{\"c\":3}\r\n";
+    let lines: Vec<_> = ndjson_lines(body).collect();
+    assert_eq!(lines, vec![(1, &b"{\"a\":1}"[..]), (4, &b"{\"b\":2}"[..]), (5, &b"{\"c\":3}"[..])]);
+    assert_eq!(ndjson_lines(b"").count(), 0);
+    assert_eq!(ndjson_lines(b"\n \r\n").count(), 0);
+    // the last line needs no ending
+    assert_eq!(ndjson_lines(b"1\n2").collect::<Vec<_>>(), vec![(1, &b"1"[..]), (2, &b"2"[..])]);
+  }
+
+  #[test]
+  fn csv_rows_become_objects() {
+    assert_eq!(
+      csv_to_json(b"id,name\n1,a\n2,\"b, c\"\n").unwrap(),
+      json!([{"id": "1", "name": "a"}, {"id": "2", "name": "b, c"}])
+    );
+    assert_eq!(csv_to_json(b"id,name\n").unwrap(), json!([]));
+    assert_eq!(csv_to_json(b"").unwrap(), json!([]));
+    // rows must have as many fields as the header
+    assert_eq!(status(csv_to_json(b"id,name\n1\n")), StatusCode::BAD_REQUEST);
+    assert_eq!(status(csv_to_json(b"id,name\n1,a,extra\n")), StatusCode::BAD_REQUEST);
+  }
+
+  #[test]
+  fn repeated_form_fields_become_arrays() {
+    assert_eq!(form_to_json(b"a=1").unwrap(), json!({"a": "1"}));
+    assert_eq!(form_to_json(b"a=1&b=x+y&a=2").unwrap(), json!({"a": ["1", "2"], "b": "x y"}));
+    assert_eq!(form_to_json(b"a=1&a=2&a=%33").unwrap(), json!({"a": ["1", "2", "3"]}));
+    assert_eq!(form_to_json(b"").unwrap(), json!({}));
+  }
+}
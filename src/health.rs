@@ -0,0 +1,81 @@
+//! `/healthz` and `/readyz` for orchestrators, both outside authentication and rate limits
+
+use crate::{durable, error::AppError, AppState};
+use axum::{
+  extract::State,
+  http::StatusCode,
+  response::{IntoResponse, Response},
+  Json,
+};
+use serde_json::{json, Map, Value};
+use std::{
+  io,
+  sync::{atomic::Ordering, Arc},
+};
+
+/// the process is up and answering
+pub async fn healthz() -> Json<Value> {
+  Json(json!({ "status": "ok" }))
+}
+
+/// whether the server should get traffic: 200 when every check passes, 503 otherwise, with the result of each check
+pub async fn readyz(State(state): State<Arc<AppState>>) -> Result<Response, AppError> {
+  // the checks touch the filesystem and the database
+  let checks = tokio::task::spawn_blocking(move || checks(&state))
+    .await
+    .map_err(|e| AppError::io("running readiness checks")(io::Error::other(e)))?;
+
+  let ready = checks.values().all(|check| check["ok"] == json!(true));
+  let (status, label) = if ready {
+    (StatusCode::OK, "ready")
+  } else {
+    (StatusCode::SERVICE_UNAVAILABLE, "not_ready")
+  };
+  Ok((status, Json(json!({ "status": label, "checks": checks }))).into_response())
+}
+
+fn outcome(result: io::Result<()>) -> Value {
+  match result {
+    Ok(()) => json!({ "ok": true }),
+    Err(e) => json!({ "ok": false, "error": e.to_string() }),
+  }
+}
+
+fn checks(state: &AppState) -> Map<String, Value> {
+  let config = &state.config;
+  let dir = &config.data_dir;
+  let mut checks = Map::new();
+
+  let exists = match std::fs::metadata(dir) {
+    Ok(metadata) if metadata.is_dir() => Ok(()),
+    Ok(_) => Err(io::Error::other(format!("{} is not a directory", dir.display()))),
+    Err(e) => Err(e),
+  };
+  checks.insert("data_dir".to_owned(), outcome(exists));
+  checks.insert("writable".to_owned(), outcome(durable::probe_writable(dir)));
+
+  let free_space = match durable::available_space(dir) {
+    Ok(available) => json!({
+      "ok": available >= config.min_free_space,
+      "available_bytes": available,
+      "min_bytes": config.min_free_space,
+    }),
+    // without a threshold there is nothing to fail
+    Err(_) if config.min_free_space == 0 => json!({ "ok": true }),
+    Err(e) => outcome(Err(e)),
+  };
+  checks.insert("free_space".to_owned(), free_space);
+
+  let mut storage = outcome(state.storage.ping());
+  storage["backend"] = json!(config.storage.backend);
+  checks.insert("storage".to_owned(), storage);
+
+  // failing first lets load balancers move traffic away while requests in flight finish
+  let stopping = state.stopping.load(Ordering::Relaxed);
+  let mut shutdown = json!({ "ok": !stopping });
+  if stopping {
+    shutdown["error"] = json!("shutting down");
+  }
+  checks.insert("shutdown".to_owned(), shutdown);
+  checks
+}
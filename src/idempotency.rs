@@ -0,0 +1,344 @@
+//! `Idempotency-Key` support for `POST /data/:name`, so clients can retry without saving a record twice
+
+use crate::{
+  auth::Identity,
+  body,
+  durable::{now, JsonLog},
+  error::AppError,
+  storage::sha256_hex,
+  AppState,
+};
+use axum::{
+  body::Body,
+  extract::{RawPathParams, Request, State},
+  http::{HeaderValue, StatusCode},
+  middleware::Next,
+  response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+  collections::HashMap,
+  io,
+  path::Path,
+  sync::{Arc, Mutex},
+};
+
+const HEADER: &str = "idempotency-key";
+const LOG_FILENAME: &str = ".idempotency.jsonl";
+/// set on responses that were answered from the cache
+const REPLAYED_HEADER: &str = "idempotent-replayed";
+/// longest key accepted, keys are usually UUIDs
+const MAX_KEY_LEN: usize = 255;
+
+/// a completed request, one line of the log
+#[derive(Serialize, Deserialize, Clone)]
+struct Entry {
+  /// name of the data, prefixed with the api key id when the request was authenticated
+  scope: String,
+  key: String,
+  /// of the request body
+  sha256: String,
+  /// unix seconds
+  created_at: i64,
+  status: u16,
+  response: Value,
+}
+
+enum Slot {
+  /// the first request with the key is still being saved
+  InFlight {
+    sha256: String,
+  },
+  Done(Entry),
+}
+
+struct Inner {
+  slots: HashMap<(String, String), Slot>,
+  /// completed entries, some of them may be expired
+  log: JsonLog,
+}
+
+/// responses to requests that carried an `Idempotency-Key`, kept for `window` seconds.
+/// completed entries are appended to `{data_dir}/.idempotency.jsonl` so they survive restarts
+pub struct Idempotency {
+  window: i64,
+  inner: Mutex<Inner>,
+}
+
+impl Idempotency {
+  /// loads the entries of a previous run that are still within `window`, dropping the rest from the log
+  pub fn open(dir: &Path, window: u64) -> io::Result<Idempotency> {
+    let window = window.try_into().unwrap_or(i64::MAX);
+
+    let mut slots = HashMap::new();
+    let mut log = JsonLog::open(dir, LOG_FILENAME, |entry: Entry| {
+      if now().saturating_sub(entry.created_at) < window {
+        slots.insert((entry.scope.clone(), entry.key.clone()), Slot::Done(entry));
+      }
+    })?;
+    log.rewrite(done(&slots))?;
+    Ok(Idempotency {
+      window,
+      inner: Mutex::new(Inner { slots, log }),
+    })
+  }
+
+  fn inner(&self) -> std::sync::MutexGuard<'_, Inner> {
+    self.inner.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  /// claims the key for a request with a body hashing to `sha256`, or returns what the first request got
+  fn begin(&self, scope: String, key: String, sha256: String) -> Result<Begin<'_>, AppError> {
+    let mut inner = self.inner();
+    let slot_key = (scope, key);
+    match inner.slots.get(&slot_key) {
+      Some(Slot::Done(entry)) if now().saturating_sub(entry.created_at) >= self.window => {}
+      Some(Slot::Done(entry)) if entry.sha256 == sha256 => return Ok(Begin::Replay(entry.clone())),
+      Some(Slot::Done(_)) => {
+        return Err(AppError::Conflict(
+          "Idempotency-Key was already used with a different body".to_owned(),
+        ))
+      }
+      Some(Slot::InFlight { sha256: first }) if *first != sha256 => {
+        return Err(AppError::Conflict(
+          "Idempotency-Key is being used by a request with a different body".to_owned(),
+        ))
+      }
+      Some(Slot::InFlight { .. }) => {
+        return Err(AppError::Conflict(
+          "a request with this Idempotency-Key is still being processed".to_owned(),
+        ))
+      }
+      None => {}
+    }
+
+    inner.slots.insert(slot_key.clone(), Slot::InFlight { sha256 });
+    Ok(Begin::New(Pending {
+      idempotency: self,
+      slot_key: Some(slot_key),
+    }))
+  }
+
+  fn complete(&self, entry: Entry) -> io::Result<()> {
+    let mut inner = self.inner();
+    let Inner { slots, log } = &mut *inner;
+    let appended = log.append(std::slice::from_ref(&entry));
+    // kept in memory even when it could not be persisted, the key is answered from there until a restart
+    slots.insert((entry.scope.clone(), entry.key.clone()), Slot::Done(entry));
+    appended?;
+
+    if log.needs_compaction(slots.len()) {
+      let now = now();
+      slots.retain(|_, slot| match slot {
+        Slot::Done(entry) => now.saturating_sub(entry.created_at) < self.window,
+        Slot::InFlight { .. } => true,
+      });
+      log.rewrite(done(slots))?;
+    }
+    Ok(())
+  }
+}
+
+/// the completed entries of `slots`, what the log holds
+fn done(slots: &HashMap<(String, String), Slot>) -> impl Iterator<Item = &Entry> {
+  slots.values().filter_map(|slot| match slot {
+    Slot::Done(entry) => Some(entry),
+    Slot::InFlight { .. } => None,
+  })
+}
+
+enum Begin<'a> {
+  Replay(Entry),
+  New(Pending<'a>),
+}
+
+/// releases the key when dropped before `complete`, so a retry of a failed request is processed again
+struct Pending<'a> {
+  idempotency: &'a Idempotency,
+  slot_key: Option<(String, String)>,
+}
+
+impl Pending<'_> {
+  /// the entry to hand to `Idempotency::complete`, the key stays claimed until then
+  fn into_entry(mut self, sha256: String, status: StatusCode, response: Value) -> Entry {
+    let (scope, key) = self.slot_key.take().expect("completed once");
+    Entry {
+      scope,
+      key,
+      sha256,
+      created_at: now(),
+      status: status.as_u16(),
+      response,
+    }
+  }
+}
+
+impl Drop for Pending<'_> {
+  fn drop(&mut self) {
+    if let Some(slot_key) = self.slot_key.take() {
+      self.idempotency.inner().slots.remove(&slot_key);
+    }
+  }
+}
+
+/// middleware for `POST /data/:name`: the first request with a key is saved and its response cached, retries with the
+/// same body get that response again, with the same record id, and retries with another body get 409
+pub async fn check(State(state): State<Arc<AppState>>, params: RawPathParams, req: Request, next: Next) -> Result<Response, AppError> {
+  let Some(value) = req.headers().get(HEADER) else {
+    return Ok(next.run(req).await);
+  };
+  let key = value
+    .to_str()
+    .ok()
+    .filter(|key| !key.is_empty() && key.len() <= MAX_KEY_LEN)
+    .ok_or_else(|| AppError::InvalidHeader(format!("Idempotency-Key must be 1 to {} visible ASCII characters", MAX_KEY_LEN)))?
+    .to_owned();
+  let name = params
+    .iter()
+    .find(|(key, _)| *key == "name")
+    .map(|(_, value)| value)
+    .unwrap_or_default();
+  let scope = match req.extensions().get::<Identity>() {
+    Some(identity) => format!("{}/{}", identity.key_id, name),
+    None => name.to_owned(),
+  };
+
+  // retries are told apart by their body, so it is read in full here even when it would otherwise be streamed
+  let limit = state.config.max_body_size(name);
+  let (parts, request_body) = req.into_parts();
+  let bytes = body::read_limited(request_body, limit).await?;
+  let sha256 = sha256_hex(&bytes);
+
+  let pending = match state.idempotency.begin(scope, key, sha256.clone())? {
+    Begin::Replay(entry) => return Ok(replay(entry)),
+    Begin::New(pending) => pending,
+  };
+  let response = next.run(Request::from_parts(parts, Body::from(bytes))).await;
+  // failures are not cached, the key is released for the retry
+  if !response.status().is_success() {
+    return Ok(response);
+  }
+
+  let (parts, response_body) = response.into_parts();
+  let bytes = axum::body::to_bytes(response_body, usize::MAX)
+    .await
+    .map_err(|e| AppError::io("reading response")(io::Error::other(e)))?;
+  let Ok(cached) = serde_json::from_slice(&bytes) else {
+    return Ok(Response::from_parts(parts, Body::from(bytes)));
+  };
+  // the record is saved at this point, so the client gets its response even when the key is only kept in memory
+  let entry = pending.into_entry(sha256, parts.status, cached);
+  let completing = state.clone();
+  let completed = tokio::task::spawn_blocking(move || completing.idempotency.complete(entry)).await;
+  if let Err(e) = completed.map_err(io::Error::other).and_then(|result| result) {
+    tracing::warn!("Failed to persist idempotency key: {}", e);
+  }
+  Ok(Response::from_parts(parts, Body::from(bytes)))
+}
+
+fn replay(entry: Entry) -> Response {
+  let status = StatusCode::from_u16(entry.status).unwrap_or(StatusCode::OK);
+  let mut response = (status, axum::Json(entry.response)).into_response();
+  response.headers_mut().insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+  response
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn conflict<T>(result: Result<T, AppError>) -> String {
+    match result {
+      Err(AppError::Conflict(message)) => message,
+      _ => panic!("no conflict"),
+    }
+  }
+
+  fn new(begin: Result<Begin<'_>, AppError>) -> Pending<'_> {
+    match begin.unwrap() {
+      Begin::New(pending) => pending,
+      Begin::Replay(_) => panic!("replayed"),
+    }
+  }
+
+  fn replayed(begin: Result<Begin<'_>, AppError>) -> Entry {
+    match begin.unwrap() {
+      Begin::Replay(entry) => entry,
+      Begin::New(_) => panic!("not replayed"),
+    }
+  }
+
+  fn begin<'a>(idempotency: &'a Idempotency, key: &str, sha256: &str) -> Result<Begin<'a>, AppError> {
+    idempotency.begin("lab".to_owned(), key.to_owned(), sha256.to_owned())
+  }
+
+  /// completes `key` as if its request was answered `age` seconds ago
+  fn complete(idempotency: &Idempotency, key: &str, sha256: &str, age: i64) {
+    let pending = new(begin(idempotency, key, sha256));
+    let mut entry = pending.into_entry(sha256.to_owned(), StatusCode::OK, json!({ "id": key }));
+    entry.created_at -= age;
+    idempotency.complete(entry).unwrap();
+  }
+
+  #[test]
+  fn retries_get_the_first_response() {
+    let dir = tempfile::tempdir().unwrap();
+    let idempotency = Idempotency::open(dir.path(), 60).unwrap();
+    complete(&idempotency, "k", "body", 0);
+
+    let entry = replayed(begin(&idempotency, "k", "body"));
+    assert_eq!((entry.status, entry.response), (200, json!({ "id": "k" })));
+    assert!(conflict(begin(&idempotency, "k", "other")).contains("different body"));
+    // keys are scoped
+    let other = idempotency.begin("other".to_owned(), "k".to_owned(), "other".to_owned());
+    assert!(matches!(other, Ok(Begin::New(_))));
+  }
+
+  #[test]
+  fn keys_in_flight_are_claimed() {
+    let dir = tempfile::tempdir().unwrap();
+    let idempotency = Idempotency::open(dir.path(), 60).unwrap();
+    let pending = new(begin(&idempotency, "k", "body"));
+
+    assert!(conflict(begin(&idempotency, "k", "body")).contains("still being processed"));
+    assert!(conflict(begin(&idempotency, "k", "other")).contains("different body"));
+
+    // a request that failed leaves the key to its retry
+    drop(pending);
+    let retry = new(begin(&idempotency, "k", "other"));
+    let entry = retry.into_entry("other".to_owned(), StatusCode::OK, json!({}));
+    idempotency.complete(entry).unwrap();
+    replayed(begin(&idempotency, "k", "other"));
+  }
+
+  #[test]
+  fn keys_can_be_reused_after_the_window() {
+    let dir = tempfile::tempdir().unwrap();
+    let idempotency = Idempotency::open(dir.path(), 60).unwrap();
+    complete(&idempotency, "k", "body", 60);
+    new(begin(&idempotency, "k", "other"));
+  }
+
+  #[test]
+  fn live_keys_survive_a_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    {
+      let idempotency = Idempotency::open(dir.path(), 60).unwrap();
+      complete(&idempotency, "live", "body", 30);
+      complete(&idempotency, "expired", "body", 90);
+      // still in flight when the process stops
+      std::mem::forget(new(begin(&idempotency, "in-flight", "body")));
+    }
+
+    let idempotency = Idempotency::open(dir.path(), 60).unwrap();
+    replayed(begin(&idempotency, "live", "body"));
+    new(begin(&idempotency, "expired", "other"));
+    new(begin(&idempotency, "in-flight", "other"));
+    // and the expired one is dropped from the log
+    let log = std::fs::read_to_string(dir.path().join(LOG_FILENAME)).unwrap();
+    assert_eq!(log.lines().count(), 1);
+    assert!(log.contains(r#""key":"live""#));
+  }
+}
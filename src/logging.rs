@@ -0,0 +1,41 @@
+//! `tracing` setup from `[log]`, and the span that ties the lines of a request to its `X-Request-Id`
+
+use crate::config::{LogConfig, LogFormat};
+use axum::http::Request;
+use std::io::IsTerminal;
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// installs the global subscriber, a non-empty `RUST_LOG` wins over `log.filter`
+pub fn init(config: &LogConfig) {
+  let directives = std::env::var(EnvFilter::DEFAULT_ENV)
+    .ok()
+    .filter(|directives| !directives.trim().is_empty());
+  let filter = EnvFilter::new(directives.as_deref().unwrap_or(&config.filter));
+  let builder = tracing_subscriber::fmt()
+    .with_env_filter(filter)
+    .with_ansi(std::io::stdout().is_terminal());
+  match config.format {
+    LogFormat::Text => builder.init(),
+    LogFormat::Pretty => builder.pretty().init(),
+    LogFormat::Json => builder
+      .json()
+      .flatten_event(true)
+      .with_current_span(true)
+      .with_span_list(false)
+      .init(),
+  }
+}
+
+/// span of a request, every line logged while handling it carries the request id.
+/// the id is set on the request before this runs, taken from the client or generated
+pub fn request_span<B>(req: &Request<B>) -> Span {
+  let request_id = req
+    .headers()
+    .get(REQUEST_ID_HEADER)
+    .and_then(|value| value.to_str().ok())
+    .unwrap_or_default();
+  tracing::info_span!("request", request_id, method = %req.method(), uri = %req.uri())
+}
@@ -1,102 +1,635 @@
-use axum::extract::ConnectInfo;
-use axum::http::HeaderMap;
+mod append;
+mod auth;
+mod body;
+mod client_ip;
+mod config;
+mod dedup;
+mod durable;
+mod error;
+mod formats;
+mod health;
+mod idempotency;
+mod logging;
+mod metrics;
+mod quota;
+mod rate_limit;
+mod retention;
+mod schemas;
+mod shutdown;
+mod storage;
+mod tls;
+mod webhooks;
+
+use auth::Identity;
+use axum::body::{Body, Bytes};
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
 use axum::{
   extract::Path,
-  http::StatusCode,
-  routing::{get, post},
-  Json, Router,
+  handler::Handler,
+  middleware,
+  routing::{delete, get, post},
+  Extension, Json, Router,
 };
+use client_ip::{ClientIp, ClientIpResolver};
+use config::{Config, OutputFormat, SaveMode};
 use core::net::SocketAddr;
+use dedup::Dedup;
+use error::AppError;
+use formats::InputFormat;
+use idempotency::Idempotency;
+use metrics::Metrics;
+use quota::Usage;
+use rate_limit::RateLimiter;
+use schemas::Schemas;
+use serde::Deserialize;
+use storage::{BodyKind, NewRecord, Storage};
+use tls::ClientCert;
 use tower_http::cors::CorsLayer;
-use tower_http::trace::TraceLayer;
-
-use serde_json::{json, to_string_pretty, Value};
-use std::{
-  env,
-  fs::File,
-  io::Write,
-  path::PathBuf,
-  time::{SystemTime, UNIX_EPOCH},
-};
+use tower_http::decompression::RequestDecompressionLayer;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tracing::{error, info, Level};
+use webhooks::Webhooks;
+
+use serde_json::{json, Value};
+use std::future::{Future, IntoFuture};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// shared by all handlers
+struct AppState {
+  config: Config,
+  storage: Arc<dyn Storage>,
+  client_ip: ClientIpResolver,
+  schemas: Schemas,
+  idempotency: Idempotency,
+  dedup: Dedup,
+  rate_limiter: RateLimiter,
+  usage: Usage,
+  metrics: Metrics,
+  webhooks: Webhooks,
+  /// set once a stop signal arrived, fails `/readyz`
+  stopping: AtomicBool,
+}
 
 #[tokio::main]
 async fn main() {
-  // initialize tracing
-  tracing_subscriber::fmt::init();
+  let config = match Config::load() {
+    Ok(config) => config,
+    Err(e) => {
+      // logging is configured by the config that failed
+      eprintln!("Error: {}", e);
+      std::process::exit(2);
+    }
+  };
+  logging::init(&config.log);
+  info!("Saving data to {}", config.data_dir.display());
+
+  let storage = match storage::open(&config) {
+    Ok(storage) => storage,
+    Err(e) => {
+      error!("failed to open {:?} storage: {}", config.storage.backend, e);
+      std::process::exit(2);
+    }
+  };
+
+  let schemas = match Schemas::load(&config.schemas_dir) {
+    Ok(schemas) => schemas,
+    Err(e) => {
+      error!("{}", e);
+      std::process::exit(2);
+    }
+  };
+  if schemas.len() > 0 {
+    info!("Loaded {} schemas from {}", schemas.len(), config.schemas_dir.display());
+  }
+
+  let dedup = match Dedup::rebuild(storage.as_ref(), &config) {
+    Ok(dedup) => dedup,
+    Err(e) => {
+      error!("failed to index records for deduplication: {}", e);
+      std::process::exit(2);
+    }
+  };
+  if dedup.len() > 0 {
+    info!("Indexed {} records for deduplication", dedup.len());
+  }
+
+  let usage = match Usage::load(storage.as_ref()) {
+    Ok(usage) => usage,
+    Err(e) => {
+      error!("failed to measure stored records: {}", e);
+      std::process::exit(2);
+    }
+  };
+
+  let idempotency = match Idempotency::open(&config.data_dir, config.idempotency_window) {
+    Ok(idempotency) => idempotency,
+    Err(e) => {
+      error!("failed to load idempotency keys: {}", e);
+      std::process::exit(2);
+    }
+  };
+
+  let webhooks = match Webhooks::open(&config.data_dir, &config.webhooks) {
+    Ok(webhooks) => webhooks,
+    Err(e) => {
+      error!("failed to load webhook deliveries: {}", e);
+      std::process::exit(2);
+    }
+  };
+  if webhooks.len() > 0 {
+    info!("Loaded {} queued or dead webhook deliveries", webhooks.len());
+  }
 
-  // build our application with a route
+  let state = Arc::new(AppState {
+    config: config.clone(),
+    storage,
+    client_ip: ClientIpResolver::new(config.trusted_proxies.clone(), config.forwarded_header),
+    schemas,
+    idempotency,
+    dedup,
+    rate_limiter: RateLimiter::new(&config),
+    usage,
+    metrics: Metrics::new(&config.metrics),
+    webhooks,
+    stopping: AtomicBool::new(false),
+  });
+  let certs = match &config.tls {
+    Some(tls) => match tls::Certificates::load(tls) {
+      Ok(certs) => Some(certs),
+      Err(e) => {
+        error!("failed to load TLS certificates: {}", e);
+        std::process::exit(2);
+      }
+    },
+    None => None,
+  };
+
+  retention::spawn(state.clone());
+  webhooks::spawn(state.clone());
+  let storage = state.storage.clone();
+  let shared = state.clone();
+
+  // build our application with a route, everything above the auth layer needs an api key once keys are configured
   let app = Router::new()
+    .route(
+      "/data/:name",
+      post(save_data.layer(middleware::from_fn_with_state(state.clone(), idempotency::check))).get(list_data),
+    )
+    .route("/data/:name/:id", get(read_data).delete(delete_data))
+    .route("/data/:name/:id/meta", get(read_metadata))
+    .route("/stats", get(stats))
+    .route("/usage", get(report_usage))
+    .route("/metrics", get(render_metrics))
+    .route("/webhooks/dead", get(webhooks::list_dead))
+    .route("/webhooks/dead/:id", delete(webhooks::discard_dead))
+    .route("/webhooks/dead/:id/retry", post(webhooks::retry_dead))
+    // limits per api key are counted after authentication, limits per address before it so failed attempts count too
+    .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit::limit))
+    .route_layer(middleware::from_fn_with_state(state.clone(), auth::authorize))
+    .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit::limit_ip))
     .route("/", get(home))
-    .route("/data/:name", post(save_data))
+    .route("/healthz", get(health::healthz))
+    .route("/readyz", get(health::readyz))
+    .fallback(not_found)
+    .layer(middleware::from_fn_with_state(state.clone(), metrics::track))
+    .with_state(state)
+    // gzip, br and zstd bodies reach handlers decompressed, so body limits count decompressed bytes
+    .layer(RequestDecompressionLayer::new())
     .layer(CorsLayer::permissive())
-    .layer(TraceLayer::new_for_http());
-  // read port from environment variable, defaults to 3000
-  let port = std::env::var("PORT").unwrap_or_else(|_| "3000".to_string());
-
-  // run our app with hyper, listening globally on port 3000
-  let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port)).await.unwrap();
-  println!("Listening on {}", listener.local_addr().unwrap());
-  axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
-    .await
-    .unwrap();
+    // the id is set first, from `X-Request-Id` or a new UUID, so the span and the response both carry it
+    .layer(PropagateRequestIdLayer::x_request_id())
+    .layer(
+      TraceLayer::new_for_http()
+        .make_span_with(logging::request_span)
+        .on_response(DefaultOnResponse::new().level(Level::INFO)),
+    )
+    .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+
+  // run our app with hyper, listening on the configured address, 0.0.0.0:3000 by default
+  let listener = match tokio::net::TcpListener::bind(config.listen_addr()).await {
+    Ok(listener) => listener,
+    Err(e) => {
+      error!("failed to listen on {}: {}", config.listen_addr(), e);
+      std::process::exit(2);
+    }
+  };
+  let scheme = if certs.is_some() { "https" } else { "http" };
+  info!("Listening on {}://{}", scheme, listener.local_addr().unwrap());
+
+  // once a signal arrives no new connections are accepted, requests in flight get `shutdown_timeout` seconds to finish
+  let (stopping_tx, mut stopping) = tokio::sync::watch::channel(false);
+  tokio::spawn(async move {
+    let signal = shutdown::signal().await;
+    shared.stopping.store(true, Ordering::Relaxed);
+    let delay = shared.config.shutdown_delay;
+    if delay > 0 {
+      info!("Received {}, failing readiness for {} seconds before stopping", signal, delay);
+      tokio::time::sleep(Duration::from_secs(delay)).await;
+    }
+    info!("Received {}, finishing requests in flight", signal);
+    let _ = stopping_tx.send(true);
+  });
+  let stopped = |mut stopping: tokio::sync::watch::Receiver<bool>| async move {
+    let _ = stopping.wait_for(|&stopping| stopping).await;
+  };
+
+  let server: Pin<Box<dyn Future<Output = std::io::Result<()>> + Send>> = match certs {
+    Some(certs) => {
+      let tls = config.tls.as_ref().expect("certificates are only loaded with [tls]");
+      if let Some(port) = tls.redirect_port {
+        let addr = SocketAddr::new(config.bind, port);
+        match tokio::net::TcpListener::bind(addr).await {
+          Ok(listener) => {
+            info!("Redirecting http://{} to HTTPS", listener.local_addr().unwrap());
+            let redirect = tls::redirect(listener, config.port, stopped(stopping.clone()));
+            tokio::spawn(async move {
+              if let Err(e) = redirect.await {
+                error!("redirect listener failed: {}", e);
+              }
+            });
+          }
+          Err(e) => {
+            error!("failed to listen on {}: {}", addr, e);
+            std::process::exit(2);
+          }
+        }
+      }
+      tls::watch(certs.clone());
+      Box::pin(tls::serve(listener, app, certs, stopped(stopping.clone())))
+    }
+    None => Box::pin(
+      axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(stopped(stopping.clone()))
+        .into_future(),
+    ),
+  };
+  let deadline = async {
+    if stopping.wait_for(|&stopping| stopping).await.is_err() {
+      std::future::pending::<()>().await;
+    }
+    tokio::time::sleep(Duration::from_secs(config.shutdown_timeout)).await;
+  };
+
+  let mut code = 0;
+  tokio::select! {
+    result = server => {
+      if let Err(e) = result {
+        error!("server failed: {}", e);
+        code = 1;
+      }
+    }
+    _ = deadline => {
+      // whatever was cut short left at most a temp file, which is swept at the next start
+      error!("requests still in flight after {} seconds, stopping anyway", config.shutdown_timeout);
+      code = 1;
+    }
+  }
+  if let Err(e) = storage.flush() {
+    error!("failed to flush storage: {}", e);
+    code = 1;
+  }
+  info!("Stopped");
+  std::process::exit(code);
 }
 
-async fn home() -> (StatusCode, String) {
-  (
-    StatusCode::OK,
-    "this is a home page of data backs. pass data to /data/:name with JSON body to save it".to_owned(),
+async fn home() -> Result<String, AppError> {
+  Ok(
+    "this is a home page of data backs. pass data to /data/:name with JSON body to save it, GET /data/:name to list saved records"
+      .to_owned(),
   )
 }
 
+async fn not_found() -> AppError {
+  AppError::NotFound("Route".to_owned())
+}
+
 /// matches [\w\d\-_]+
 fn is_valid_name(name: &str) -> bool {
   name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_')
 }
 
+#[derive(Deserialize)]
+struct SaveParams {
+  /// overrides the mode configured for the name
+  mode: Option<SaveMode>,
+}
+
+// extractors are the arguments of a handler
+#[allow(clippy::too_many_arguments)]
 async fn save_data(
-  // this argument tells axum to parse the request body
+  State(state): State<Arc<AppState>>,
   Path(name): Path<String>,
+  Query(params): Query<SaveParams>,
   headers: HeaderMap,
-  ConnectInfo(addr): ConnectInfo<SocketAddr>,
-  // as JSON into a `Data` type
-  Json(payload): Json<Value>,
-) -> (StatusCode, String) {
-  let data = to_string_pretty(&payload).unwrap();
-  let remote_addr = headers
-    .get("X-Forwarded-For")
-    .map(|addr| addr.to_str().unwrap_or("none"))
-    .unwrap_or("none");
-
-  println!("Data received for {:?} {}: {}", addr, name, data.len());
+  ClientIp { ip: client_ip, peer }: ClientIp,
+  identity: Option<Extension<Identity>>,
+  client_cert: Option<Extension<ClientCert>>,
+  // read by hand to apply the limit of the name and to stream large documents
+  body: Body,
+) -> Result<Json<Value>, AppError> {
+  if !is_valid_name(&name) {
+    return Err(AppError::InvalidName(name));
+  }
+  let input = InputFormat::from_headers(&headers)?;
+  // opaque bytes cannot be validated, so names with a schema only take documents
+  if input == InputFormat::Binary && state.schemas.contains(&name) {
+    return Err(AppError::InvalidBody {
+      status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+      message: format!(
+        "{} has a JSON Schema, `Content-Type: application/octet-stream` cannot be validated against it",
+        name
+      ),
+    });
+  }
+
+  // compressed bodies have no `Content-Length` any more and are only cut off while they are read
+  let limit = state.config.max_body_size(&name);
+  let content_length = body::content_length(&headers);
+  if content_length.is_some_and(|len| len > limit) {
+    return Err(AppError::PayloadTooLarge(limit));
+  }
+
+  let client_subject = client_cert.map(|Extension(cert)| cert.subject);
+  info!(
+    %client_ip,
+    name,
+    key_id = identity.as_ref().map(|Extension(identity)| identity.key_id.as_str()),
+    client_subject = client_subject.as_deref(),
+    content_length,
+    "Data received"
+  );
 
+  let mode = params.mode.unwrap_or_else(|| state.config.name(&name).mode);
+  let record = NewRecord {
+    name,
+    addr: client_ip.to_string(),
+    peer: peer.to_string(),
+    client_subject,
+    received_at: chrono::Utc::now(),
+    headers: state
+      .config
+      .metadata_headers
+      .iter()
+      .filter_map(|key| {
+        let values: Vec<_> = headers.get_all(key).iter().filter_map(|v| v.to_str().ok()).collect();
+        (!values.is_empty()).then(|| (key.clone(), values.join(", ")))
+      })
+      .collect(),
+    kind: BodyKind::Json,
+    body: vec![],
+  };
+
+  // binary bodies and large documents go to storage as they arrive, only the syntax of documents can be checked on the way,
+  // so documents are only streamed when they would be stored as they are anyway
+  let streamable = mode == SaveMode::File
+    && state.config.format(&record.name) == OutputFormat::Raw
+    && !state.schemas.contains(&record.name)
+    && state.config.dedup_window(&record.name).is_none();
+  let large = content_length.is_none_or(|len| len > state.config.stream_threshold);
+  if input == InputFormat::Binary || (input == InputFormat::Json && streamable && large) {
+    let kind = if input == InputFormat::Binary {
+      BodyKind::Binary
+    } else {
+      BodyKind::Json
+    };
+    let record = NewRecord { kind, ..record };
+    let name = record.name.clone();
+    let reservation = state.usage.check(&state.config, &name, content_length.unwrap_or(0), 1)?;
+    let meta = body::store_streaming(state.storage.clone(), record.clone(), body, limit, reservation).await?;
+    state.metrics.received(&name, meta.size);
+    state.metrics.saved(&name, meta.size);
+    info!(id = meta.id, size = meta.size, "Data streamed");
+    return blocking(&state, move |state| {
+      // the hash was computed while the body was written, so it is read back from storage
+      state
+        .webhooks
+        .notify(&record, &meta, SaveMode::File, || match state.storage.metadata(&name, &meta.id) {
+          Ok(Some(metadata)) => metadata,
+          _ => record.metadata(meta.size, String::new()),
+        });
+      Ok(Json(json!(meta)))
+    })
+    .await;
+  }
+
+  let bytes = body::read_limited(body, limit).await?;
+  state.metrics.received(&record.name, bytes.len() as u64);
+  let format = state.config.format(&record.name);
+  let meta = match input {
+    InputFormat::NdJson => {
+      // every line is checked before any of them is stored
+      let mut lines = vec![];
+      for (number, line) in formats::ndjson_lines(&bytes) {
+        let line = bytes.slice_ref(line);
+        let body = encode_document(&state, &record.name, line, None, format, mode).map_err(|e| match e {
+          AppError::InvalidBody { status, message } => AppError::InvalidBody {
+            status,
+            message: format!("line {}: {}", number, message),
+          },
+          e => e,
+        })?;
+        lines.push(body);
+      }
+
+      // and the whole batch has to fit, only deduplicated lines could make it fit anyway.
+      // each line reserves its own share as it is stored
+      let bytes = lines.iter().map(|line| line.len() as u64).sum();
+      let records = if mode == SaveMode::File { lines.len() as u64 } else { 0 };
+      drop(state.usage.check(&state.config, &record.name, bytes, records)?);
+
+      let metas = blocking(&state, move |state| {
+        lines
+          .into_iter()
+          .map(|body| store(state, &NewRecord { body, ..record.clone() }, mode))
+          .collect::<Result<Vec<_>, _>>()
+      })
+      .await?;
+      return Ok(Json(json!({ "records": metas })));
+    }
+    InputFormat::Csv | InputFormat::Form => {
+      let payload = if input == InputFormat::Csv {
+        formats::csv_to_json(&bytes)?
+      } else {
+        formats::form_to_json(&bytes)?
+      };
+      // the bytes the client sent are not JSON, so there is nothing raw to keep
+      let format = if format == OutputFormat::Raw {
+        OutputFormat::Compact
+      } else {
+        format
+      };
+      let body = encode_document(&state, &record.name, bytes, Some(payload), format, mode)?;
+      blocking(&state, move |state| store(state, &NewRecord { body, ..record }, mode)).await?
+    }
+    InputFormat::Json | InputFormat::Binary => {
+      let body = encode_document(&state, &record.name, bytes, None, format, mode)?;
+      blocking(&state, move |state| store(state, &NewRecord { body, ..record }, mode)).await?
+    }
+  };
+
+  Ok(Json(json!(meta)))
+}
+
+/// checks a JSON document, against the schema of `name` if it has one, and encodes it in `format`.
+/// `payload` is the document when it was converted from another format, otherwise `raw` is parsed when needed
+fn encode_document(
+  state: &AppState,
+  name: &str,
+  raw: Bytes,
+  payload: Option<Value>,
+  format: OutputFormat,
+  mode: SaveMode,
+) -> Result<Vec<u8>, AppError> {
+  // the tree is only built when re-encoding or a schema needs it
+  let payload = match payload {
+    Some(payload) => Some(payload),
+    None if format != OutputFormat::Raw || state.schemas.contains(name) => Some(body::parse_json(&raw)?),
+    None => {
+      body::check_json(&raw)?;
+      None
+    }
+  };
+  if let Some(payload) = &payload {
+    state.schemas.validate(name, payload).map_err(AppError::SchemaViolation)?;
+  }
+  body::encode(raw, payload.as_ref(), format, mode)
+}
+
+/// runs `f` on the blocking pool, saving fsyncs files and waits for locks like the SQLite connection's,
+/// which would hold up every other request on the same worker thread
+async fn blocking<T: Send + 'static>(
+  state: &Arc<AppState>,
+  f: impl FnOnce(&AppState) -> Result<T, AppError> + Send + 'static,
+) -> Result<T, AppError> {
+  let state = state.clone();
+  // keeps the request id on the log lines
+  let span = tracing::Span::current();
+  tokio::task::spawn_blocking(move || span.in_scope(|| f(&state)))
+    .await
+    .map_err(|e| AppError::io("saving record")(std::io::Error::other(e)))?
+}
+
+/// saves an encoded document, or points to the stored one when the name deduplicates and an identical one is stored
+fn store(state: &AppState, record: &NewRecord, mode: SaveMode) -> Result<Value, AppError> {
+  let put = || {
+    let meta = match mode {
+      SaveMode::File => {
+        let reservation = state.usage.check(&state.config, &record.name, record.body.len() as u64, 1)?;
+        let meta = state.storage.put(record).map_err(AppError::io("saving record"))?;
+        reservation.keep(meta.size, 1);
+        meta
+      }
+      // a line added to an existing log is not a record of its own, the first line of the day starts one
+      SaveMode::Append => {
+        let (bytes, records) = state.storage.append_usage(record).map_err(AppError::io("saving record"))?;
+        let reservation = state.usage.check(&state.config, &record.name, bytes, records)?;
+        let appended = state.storage.append(record).map_err(AppError::io("saving record"))?;
+        reservation.keep(appended.bytes, appended.created.into());
+        appended.meta
+      }
+    };
+    state.metrics.saved(&record.name, meta.size);
+    info!(id = meta.id, size = meta.size, "Data saved");
+    state.webhooks.notify(record, &meta, mode, || {
+      record.metadata(meta.size, storage::sha256_hex(&record.body))
+    });
+    Ok(meta)
+  };
+
+  let dedup = state.config.dedup_window(&record.name).zip(dedup::canonical_hash(&record.body));
+  let Some((window, hash)) = dedup else {
+    return Ok(json!(put()?));
+  };
+  let (meta, deduplicated) = state.dedup.store_once(&record.name, hash, window, record.received_at, put)?;
+  let mut response = json!(meta);
+  if deduplicated {
+    info!(id = meta.id, "Data is identical to a stored record");
+    response["deduplicated"] = json!(true);
+  }
+  Ok(response)
+}
+
+async fn list_data(State(state): State<Arc<AppState>>, Path(name): Path<String>) -> Result<Json<Value>, AppError> {
+  if !is_valid_name(&name) {
+    return Err(AppError::InvalidName(name));
+  }
+
+  let records = state.storage.list(&name).map_err(AppError::io("listing records"))?;
+  Ok(Json(json!({ "records": records })))
+}
+
+async fn read_data(State(state): State<Arc<AppState>>, Path((name, id)): Path<(String, String)>) -> Result<Response, AppError> {
   if !is_valid_name(&name) {
-    return (StatusCode::BAD_REQUEST, "Invalid name".to_string());
+    return Err(AppError::InvalidName(name));
+  }
+  if !is_valid_name(&id) {
+    return Err(AppError::InvalidName(id));
   }
 
-  let filename = generate_filename(&name, remote_addr);
-  let current_dir = env::current_dir().unwrap();
-  let path = PathBuf::from(format!("{}/data/{}", current_dir.display(), filename));
+  match state.storage.get(&name, &id).map_err(AppError::io("reading record"))? {
+    Some((kind, body)) => Ok(([(header::CONTENT_TYPE, kind.content_type())], body).into_response()),
+    None => Err(AppError::NotFound("Record".to_owned())),
+  }
+}
 
-  // Create directory if it doesn't exist
-  if !path.parent().unwrap().exists() {
-    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+/// when and from where a record was received, with its size and hash
+async fn read_metadata(State(state): State<Arc<AppState>>, Path((name, id)): Path<(String, String)>) -> Result<Json<Value>, AppError> {
+  if !is_valid_name(&name) {
+    return Err(AppError::InvalidName(name));
+  }
+  if !is_valid_name(&id) {
+    return Err(AppError::InvalidName(id));
   }
 
-  let mut file = File::create(&path).unwrap();
-  file.write_all(data.as_bytes()).unwrap();
+  match state.storage.metadata(&name, &id).map_err(AppError::io("reading metadata"))? {
+    Some(metadata) => Ok(Json(json!(metadata))),
+    None => Err(AppError::NotFound("Record".to_owned())),
+  }
+}
 
-  println!("Data saved to {}", filename);
+async fn delete_data(State(state): State<Arc<AppState>>, Path((name, id)): Path<(String, String)>) -> Result<StatusCode, AppError> {
+  if !is_valid_name(&name) {
+    return Err(AppError::InvalidName(name));
+  }
+  if !is_valid_name(&id) {
+    return Err(AppError::InvalidName(id));
+  }
 
-  (StatusCode::OK, json!({ "filename": filename }).to_string())
+  if let Some(bytes) = state.storage.delete(&name, &id).map_err(AppError::io("deleting record"))? {
+    state.usage.remove(&name, bytes);
+    state.dedup.forget(&name, &id);
+    Ok(StatusCode::NO_CONTENT)
+  } else {
+    Err(AppError::NotFound("Record".to_owned()))
+  }
 }
 
-// Generates a filename with date in the format YYYY-MM-DD.json
-fn generate_filename(name: &str, addr: &str) -> String {
-  let now = SystemTime::now();
-  let duration = now.duration_since(UNIX_EPOCH).unwrap();
-  let seconds = duration.as_secs();
+/// records and bytes stored per name
+async fn stats(State(state): State<Arc<AppState>>) -> Result<Json<Value>, AppError> {
+  let names = state.storage.stats().map_err(AppError::io("collecting stats"))?;
+  Ok(Json(json!({ "names": names })))
+}
 
-  let date = chrono::DateTime::from_timestamp(seconds as i64, 0).expect("Invalid timestamp");
+/// stored records and bytes per name next to the quotas
+async fn report_usage(State(state): State<Arc<AppState>>) -> Json<Value> {
+  let config = &state.config;
+  let names = state.usage.report(config);
+  let records: u64 = names.iter().map(|usage| usage.stats.records).sum();
+  let bytes: u64 = names.iter().map(|usage| usage.stats.bytes).sum();
+  Json(json!({
+    "names": names,
+    "total": { "records": records, "bytes": bytes, "max_records": config.max_records, "max_bytes": config.max_bytes },
+  }))
+}
 
-  format!("{}-{}-{}.json", name, date.format("%Y-%m-%d"), addr.replace(['.', ':'], "_"))
+/// Prometheus text format, labels by name are bounded by `[metrics]`
+async fn render_metrics(State(state): State<Arc<AppState>>) -> Response {
+  let body = state.metrics.render(&state.usage.report(&state.config));
+  ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
 }
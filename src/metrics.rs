@@ -0,0 +1,343 @@
+//! counters for `/metrics`, rendered in the Prometheus text format
+
+use crate::{
+  config::{matches_name, MetricsConfig},
+  error::ErrorCode,
+  quota::NameUsage,
+  AppState,
+};
+use axum::{
+  extract::{MatchedPath, Request, State},
+  http::{Method, StatusCode},
+  middleware::Next,
+  response::Response,
+};
+use std::{
+  collections::{BTreeMap, HashSet},
+  fmt::Write,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+/// upper bounds of the latency buckets, in seconds
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+/// label of the names that did not get one of their own
+const OTHER: &str = "_other";
+
+#[derive(Default)]
+struct Histogram {
+  /// not cumulative, summed up when rendered
+  buckets: [u64; BUCKETS.len()],
+  count: u64,
+  sum: f64,
+}
+
+impl Histogram {
+  fn observe(&mut self, seconds: f64) {
+    if let Some(bucket) = BUCKETS.iter().position(|&le| seconds <= le) {
+      self.buckets[bucket] += 1;
+    }
+    self.count += 1;
+    self.sum += seconds;
+  }
+}
+
+#[derive(Default)]
+struct Inner {
+  /// names that got a label of their own while no allow-list is configured
+  labelled: HashSet<String>,
+  /// by route, method and status
+  requests: BTreeMap<(String, &'static str, u16), u64>,
+  /// by route and method
+  durations: BTreeMap<(String, &'static str), Histogram>,
+  /// by error code
+  rejections: BTreeMap<&'static str, u64>,
+  /// the rest by name label
+  received_bytes: BTreeMap<String, u64>,
+  written_bytes: BTreeMap<String, u64>,
+  records_saved: BTreeMap<String, u64>,
+}
+
+pub struct Metrics {
+  config: MetricsConfig,
+  inner: Mutex<Inner>,
+}
+
+/// methods outside the standard ones are arbitrary strings, they share one label
+fn method_label(method: &Method) -> &'static str {
+  match *method {
+    Method::GET => "GET",
+    Method::POST => "POST",
+    Method::PUT => "PUT",
+    Method::DELETE => "DELETE",
+    Method::HEAD => "HEAD",
+    Method::OPTIONS => "OPTIONS",
+    Method::PATCH => "PATCH",
+    _ => "other",
+  }
+}
+
+/// quotes and escapes a label value
+fn quote(value: &str) -> String {
+  format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
+
+impl Metrics {
+  pub fn new(config: &MetricsConfig) -> Self {
+    Metrics {
+      config: config.clone(),
+      inner: Mutex::new(Inner::default()),
+    }
+  }
+
+  fn inner(&self) -> std::sync::MutexGuard<'_, Inner> {
+    self.inner.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  /// the `name` label of a name: itself when allowed or while there is room, `_other` otherwise
+  fn name_label(&self, inner: &mut Inner, name: &str) -> String {
+    if !self.config.names.is_empty() {
+      let allowed = self.config.names.iter().any(|pattern| matches_name(pattern, name));
+      return if allowed { name.to_owned() } else { OTHER.to_owned() };
+    }
+    if inner.labelled.contains(name) {
+      return name.to_owned();
+    }
+    if inner.labelled.len() < self.config.max_names {
+      inner.labelled.insert(name.to_owned());
+      return name.to_owned();
+    }
+    OTHER.to_owned()
+  }
+
+  fn request(&self, route: String, method: &'static str, status: StatusCode, elapsed: Duration, error: Option<ErrorCode>) {
+    let mut inner = self.inner();
+    *inner.requests.entry((route.clone(), method, status.as_u16())).or_default() += 1;
+    inner.durations.entry((route, method)).or_default().observe(elapsed.as_secs_f64());
+    if let Some(ErrorCode(code)) = error.filter(|_| !status.is_server_error()) {
+      *inner.rejections.entry(code).or_default() += 1;
+    }
+  }
+
+  /// body bytes of a save request, after any decompression
+  pub fn received(&self, name: &str, bytes: u64) {
+    let mut inner = self.inner();
+    let label = self.name_label(&mut inner, name);
+    *inner.received_bytes.entry(label).or_default() += bytes;
+  }
+
+  /// a record or append log line that was stored
+  pub fn saved(&self, name: &str, bytes: u64) {
+    let mut inner = self.inner();
+    let label = self.name_label(&mut inner, name);
+    *inner.written_bytes.entry(label.clone()).or_default() += bytes;
+    *inner.records_saved.entry(label).or_default() += 1;
+  }
+
+  /// everything counted so far, with the storage usage of every name
+  pub fn render(&self, usage: &[NameUsage]) -> String {
+    let mut inner = self.inner();
+    let mut stored: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+    for name in usage {
+      let label = self.name_label(&mut inner, &name.stats.name);
+      let entry = stored.entry(label).or_default();
+      entry.0 += name.stats.records;
+      entry.1 += name.stats.bytes;
+    }
+
+    let mut out = String::new();
+    header(
+      &mut out,
+      "data_backs_http_requests_total",
+      "counter",
+      "requests by route, method and status",
+    );
+    for ((route, method, status), count) in &inner.requests {
+      let _ = writeln!(
+        out,
+        "data_backs_http_requests_total{{route={},method=\"{}\",status=\"{}\"}} {}",
+        quote(route),
+        method,
+        status,
+        count
+      );
+    }
+
+    header(
+      &mut out,
+      "data_backs_http_request_duration_seconds",
+      "histogram",
+      "time until the response started, by route and method",
+    );
+    for ((route, method), histogram) in &inner.durations {
+      let labels = format!("route={},method=\"{}\"", quote(route), method);
+      let mut cumulative = 0;
+      for (le, count) in BUCKETS.iter().zip(histogram.buckets) {
+        cumulative += count;
+        let _ = writeln!(
+          out,
+          "data_backs_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+          labels, le, cumulative
+        );
+      }
+      let _ = writeln!(
+        out,
+        "data_backs_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+        labels, histogram.count
+      );
+      let _ = writeln!(out, "data_backs_http_request_duration_seconds_sum{{{}}} {}", labels, histogram.sum);
+      let _ = writeln!(
+        out,
+        "data_backs_http_request_duration_seconds_count{{{}}} {}",
+        labels, histogram.count
+      );
+    }
+
+    header(
+      &mut out,
+      "data_backs_rejected_requests_total",
+      "counter",
+      "requests refused with a client error, by error code",
+    );
+    for (code, count) in &inner.rejections {
+      let _ = writeln!(out, "data_backs_rejected_requests_total{{code=\"{}\"}} {}", code, count);
+    }
+
+    let per_name = [
+      (
+        "data_backs_received_bytes_total",
+        "counter",
+        "body bytes of save requests",
+        &inner.received_bytes,
+      ),
+      (
+        "data_backs_written_bytes_total",
+        "counter",
+        "body bytes stored",
+        &inner.written_bytes,
+      ),
+      (
+        "data_backs_records_saved_total",
+        "counter",
+        "records and append log lines stored",
+        &inner.records_saved,
+      ),
+    ];
+    for (metric, kind, help, values) in per_name {
+      header(&mut out, metric, kind, help);
+      for (name, value) in values {
+        let _ = writeln!(out, "{}{{name={}}} {}", metric, quote(name), value);
+      }
+    }
+
+    header(&mut out, "data_backs_stored_records", "gauge", "records in storage");
+    for (name, (records, _)) in &stored {
+      let _ = writeln!(out, "data_backs_stored_records{{name={}}} {}", quote(name), records);
+    }
+    header(&mut out, "data_backs_stored_bytes", "gauge", "bytes in storage");
+    for (name, (_, bytes)) in &stored {
+      let _ = writeln!(out, "data_backs_stored_bytes{{name={}}} {}", quote(name), bytes);
+    }
+    out
+  }
+}
+
+fn header(out: &mut String, metric: &str, kind: &str, help: &str) {
+  let _ = writeln!(out, "# HELP {} {}", metric, help);
+  let _ = writeln!(out, "# TYPE {} {}", metric, kind);
+}
+
+/// middleware around every route, counts requests by the route pattern they matched so ids do not become labels
+pub async fn track(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+  let route = req
+    .extensions()
+    .get::<MatchedPath>()
+    .map_or_else(|| "unmatched".to_owned(), |path| path.as_str().to_owned());
+  let method = method_label(req.method());
+  let started = Instant::now();
+
+  let response = next.run(req).await;
+  state.metrics.request(
+    route,
+    method,
+    response.status(),
+    started.elapsed(),
+    response.extensions().get::<ErrorCode>().copied(),
+  );
+  response
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::NameStats;
+
+  fn usage(names: &[&str]) -> Vec<NameUsage> {
+    names
+      .iter()
+      .map(|name| NameUsage {
+        stats: NameStats {
+          name: name.to_string(),
+          records: 1,
+          bytes: 10,
+        },
+        max_bytes: None,
+        max_records: None,
+      })
+      .collect()
+  }
+
+  /// every `name` label in the rendered metrics
+  fn labels(rendered: &str) -> HashSet<String> {
+    rendered
+      .split("name=\"")
+      .skip(1)
+      .filter_map(|rest| rest.split_once('"'))
+      .map(|(name, _)| name.to_owned())
+      .collect()
+  }
+
+  fn set(names: &[&str]) -> HashSet<String> {
+    names.iter().map(|name| name.to_string()).collect()
+  }
+
+  #[test]
+  fn names_outside_the_allow_list_are_other() {
+    let metrics = Metrics::new(&MetricsConfig {
+      names: vec!["lab".to_owned(), "sensor-*".to_owned()],
+      max_names: 1,
+    });
+    for name in ["lab", "sensor-1", "sensor-2", "lab2", "field", "sensor"] {
+      metrics.saved(name, 1);
+    }
+    let rendered = metrics.render(&usage(&["lab", "elsewhere"]));
+    // the allow-list is not limited by `max_names`
+    assert_eq!(labels(&rendered), set(&["lab", "sensor-1", "sensor-2", OTHER]));
+    assert!(rendered.contains("data_backs_records_saved_total{name=\"_other\"} 3"));
+    assert!(rendered.contains("data_backs_stored_records{name=\"_other\"} 1"));
+  }
+
+  #[test]
+  fn names_past_max_names_are_other() {
+    let metrics = Metrics::new(&MetricsConfig {
+      names: vec![],
+      max_names: 2,
+    });
+    metrics.received("a", 1);
+    metrics.saved("b", 1);
+    metrics.saved("c", 1);
+    metrics.received("a", 1);
+    let rendered = metrics.render(&usage(&["a", "b", "c", "d", "e"]));
+    // names stored before the process started do not get labels past the limit either
+    assert_eq!(labels(&rendered), set(&["a", "b", OTHER]));
+    assert!(rendered.contains("data_backs_received_bytes_total{name=\"a\"} 2"));
+    assert!(rendered.contains("data_backs_stored_records{name=\"_other\"} 3"));
+    assert!(rendered.contains("data_backs_stored_bytes{name=\"_other\"} 30"));
+
+    // later names stay `_other`, whether read from usage or saved
+    let rendered = metrics.render(&usage(&["f", "g"]));
+    assert_eq!(labels(&rendered), set(&["a", "b", OTHER]));
+    metrics.saved("h", 1);
+    assert!(metrics.render(&[]).contains("data_backs_records_saved_total{name=\"_other\"} 2"));
+  }
+}
@@ -0,0 +1,357 @@
+//! `max_bytes` and `max_records`, globally and per name, checked against running counters of what is stored
+
+use crate::{
+  config::Config,
+  error::AppError,
+  storage::{NameStats, Storage},
+};
+use serde::Serialize;
+use std::{
+  collections::BTreeMap,
+  io,
+  sync::{Arc, Mutex, MutexGuard},
+};
+
+/// stored records and bytes per name, loaded from storage at startup and kept up to date by saves and deletes.
+/// the retention task reloads it. a daily append log counts as one record
+pub struct Usage {
+  by_name: Arc<Mutex<BTreeMap<String, NameStats>>>,
+}
+
+/// bytes and records counted for a save in progress, released when it is dropped before `keep`
+pub struct Reservation {
+  by_name: Arc<Mutex<BTreeMap<String, NameStats>>>,
+  name: String,
+  bytes: u64,
+  records: u64,
+  max_bytes: MaxBytes,
+  kept: bool,
+}
+
+/// usage of a name next to its limits, served by `/usage`
+#[derive(Serialize, Debug)]
+pub struct NameUsage {
+  #[serde(flatten)]
+  pub stats: NameStats,
+  pub max_bytes: Option<u64>,
+  pub max_records: Option<u64>,
+}
+
+/// `max_bytes` of a name and the global one
+type MaxBytes = (Option<u64>, Option<u64>);
+
+fn max_bytes(config: &Config, name: &str) -> MaxBytes {
+  (config.names.get(name).and_then(|n| n.max_bytes), config.max_bytes)
+}
+
+fn room(by_name: &BTreeMap<String, NameStats>, (name_max, global_max): MaxBytes, name: &str) -> Option<(u64, String)> {
+  let name_quota = name_max.map(|max| {
+    let used = by_name.get(name).map_or(0, |stats| stats.bytes);
+    (max.saturating_sub(used), format!("max_bytes of {} ({})", name, max))
+  });
+  let global_quota = global_max.map(|max| {
+    let used = by_name.values().map(|stats| stats.bytes).sum::<u64>();
+    (max.saturating_sub(used), format!("max_bytes ({})", max))
+  });
+  // the name's own quota is reported when both are as tight
+  [name_quota, global_quota].into_iter().flatten().min_by_key(|(room, _)| *room)
+}
+
+fn lock(by_name: &Mutex<BTreeMap<String, NameStats>>) -> MutexGuard<'_, BTreeMap<String, NameStats>> {
+  by_name.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn add(by_name: &mut BTreeMap<String, NameStats>, name: &str, bytes: i64, records: i64) {
+  let stats = by_name.entry(name.to_owned()).or_insert_with(|| NameStats {
+    name: name.to_owned(),
+    ..NameStats::default()
+  });
+  stats.bytes = stats.bytes.saturating_add_signed(bytes);
+  stats.records = stats.records.saturating_add_signed(records);
+}
+
+impl Usage {
+  pub fn load(storage: &dyn Storage) -> io::Result<Usage> {
+    let usage = Usage {
+      by_name: Arc::new(Mutex::new(BTreeMap::new())),
+    };
+    usage.reload(storage)?;
+    Ok(usage)
+  }
+
+  fn by_name(&self) -> MutexGuard<'_, BTreeMap<String, NameStats>> {
+    lock(&self.by_name)
+  }
+
+  pub fn reload(&self, storage: &dyn Storage) -> io::Result<()> {
+    let stats = storage.stats()?;
+    *self.by_name() = stats.into_iter().map(|stats| (stats.name.clone(), stats)).collect();
+    Ok(())
+  }
+
+  /// reserves `bytes` in `records` new records for `name`, failing when they would go past a quota.
+  /// checking and counting under the same lock keeps concurrent saves from sharing the last room
+  pub fn check(&self, config: &Config, name: &str, bytes: u64, records: u64) -> Result<Reservation, AppError> {
+    let mut by_name = self.by_name();
+    let max_bytes = max_bytes(config, name);
+    if let Some((room, quota)) = room(&by_name, max_bytes, name) {
+      if bytes > room {
+        return Err(AppError::QuotaExceeded(quota));
+      }
+    }
+
+    let name_config = config.names.get(name);
+    let used_records = by_name.get(name).map_or(0, |stats| stats.records);
+    if let Some(max) = name_config.and_then(|n| n.max_records) {
+      if used_records + records > max {
+        return Err(AppError::QuotaExceeded(format!("max_records of {} ({})", name, max)));
+      }
+    }
+    if let Some(max) = config.max_records {
+      if by_name.values().map(|stats| stats.records).sum::<u64>() + records > max {
+        return Err(AppError::QuotaExceeded(format!("max_records ({})", max)));
+      }
+    }
+
+    add(&mut by_name, name, bytes as i64, records as i64);
+    Ok(Reservation {
+      by_name: self.by_name.clone(),
+      name: name.to_owned(),
+      bytes,
+      records,
+      max_bytes,
+      kept: false,
+    })
+  }
+
+  pub fn remove(&self, name: &str, bytes: u64) {
+    if let Some(stats) = self.by_name().get_mut(name) {
+      stats.bytes = stats.bytes.saturating_sub(bytes);
+      stats.records = stats.records.saturating_sub(1);
+    }
+  }
+
+  /// every name with records or a quota, ordered by name
+  pub fn report(&self, config: &Config) -> Vec<NameUsage> {
+    let by_name = self.by_name();
+    let mut names: Vec<&String> = by_name.keys().collect();
+    names.extend(
+      config
+        .names
+        .iter()
+        .filter(|(_, n)| n.max_bytes.is_some() || n.max_records.is_some())
+        .map(|(name, _)| name),
+    );
+    names.sort();
+    names.dedup();
+
+    names
+      .into_iter()
+      .map(|name| {
+        let stats = by_name.get(name);
+        let name_config = config.names.get(name);
+        NameUsage {
+          stats: NameStats {
+            name: name.clone(),
+            records: stats.map_or(0, |s| s.records),
+            bytes: stats.map_or(0, |s| s.bytes),
+          },
+          max_bytes: name_config.and_then(|n| n.max_bytes),
+          max_records: name_config.and_then(|n| n.max_records),
+        }
+      })
+      .collect()
+  }
+}
+
+impl Reservation {
+  /// grows the reservation to `bytes` for a body whose length is only known as it arrives,
+  /// failing with the quota it would exceed
+  pub fn cover(&mut self, bytes: u64) -> Result<(), String> {
+    if bytes <= self.bytes {
+      return Ok(());
+    }
+    let mut by_name = lock(&self.by_name);
+    if let Some((room, quota)) = room(&by_name, self.max_bytes, &self.name) {
+      if bytes - self.bytes > room {
+        return Err(quota);
+      }
+    }
+    add(&mut by_name, &self.name, (bytes - self.bytes) as i64, 0);
+    self.bytes = bytes;
+    Ok(())
+  }
+
+  /// counts what the save actually stored in place of what was reserved
+  pub fn keep(mut self, bytes: u64, records: u64) {
+    let mut by_name = lock(&self.by_name);
+    add(
+      &mut by_name,
+      &self.name,
+      bytes as i64 - self.bytes as i64,
+      records as i64 - self.records as i64,
+    );
+    self.kept = true;
+  }
+}
+
+impl Drop for Reservation {
+  fn drop(&mut self) {
+    if !self.kept {
+      add(&mut lock(&self.by_name), &self.name, -(self.bytes as i64), -(self.records as i64));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    config::NameConfig,
+    storage::{FsStorage, NewRecord},
+  };
+
+  fn config() -> Config {
+    let mut config = Config {
+      max_bytes: Some(150),
+      max_records: Some(10),
+      ..Config::default()
+    };
+    let name = NameConfig {
+      max_bytes: Some(100),
+      max_records: Some(2),
+      ..NameConfig::default()
+    };
+    config.names.insert("small".to_owned(), name);
+    config
+  }
+
+  fn usage() -> Usage {
+    Usage {
+      by_name: Arc::new(Mutex::new(BTreeMap::new())),
+    }
+  }
+
+  fn add(usage: &Usage, name: &str, bytes: u64, records: u64) {
+    usage.check(&Config::default(), name, bytes, records).unwrap().keep(bytes, records);
+  }
+
+  fn exceeded(result: Result<Reservation, AppError>) -> String {
+    match result {
+      Err(AppError::QuotaExceeded(quota)) => quota,
+      _ => panic!("quota not exceeded"),
+    }
+  }
+
+  fn used(usage: &Usage, name: &str) -> (u64, u64) {
+    usage.by_name().get(name).map_or((0, 0), |stats| (stats.bytes, stats.records))
+  }
+
+  #[test]
+  fn quotas_of_the_name_apply_first() {
+    let (config, usage) = (config(), usage());
+    add(&usage, "small", 90, 1);
+    assert!(usage.check(&config, "small", 10, 1).is_ok());
+    assert_eq!(exceeded(usage.check(&config, "small", 11, 1)), "max_bytes of small (100)");
+
+    add(&usage, "small", 0, 1);
+    assert_eq!(exceeded(usage.check(&config, "small", 0, 1)), "max_records of small (2)");
+    // lines added to an existing log are not new records
+    assert!(usage.check(&config, "small", 0, 0).is_ok());
+  }
+
+  #[test]
+  fn global_quotas_count_every_name() {
+    let (config, usage) = (config(), usage());
+    add(&usage, "small", 90, 2);
+    add(&usage, "other", 50, 7);
+    assert_eq!(exceeded(usage.check(&config, "other", 11, 1)), "max_bytes (150)");
+    assert!(usage.check(&config, "other", 10, 1).is_ok());
+    add(&usage, "other", 0, 1);
+    assert_eq!(exceeded(usage.check(&config, "other", 0, 1)), "max_records (10)");
+
+    // a delete makes room again
+    usage.remove("other", 50);
+    assert!(usage.check(&config, "other", 50, 1).is_ok());
+  }
+
+  #[test]
+  fn reservations_hold_the_room_until_released() {
+    let (config, usage) = (config(), usage());
+    let first = usage.check(&config, "small", 60, 1).unwrap();
+    let second = usage.check(&config, "small", 40, 1).unwrap();
+    // both records are taken while the saves are still running
+    assert_eq!(exceeded(usage.check(&config, "small", 1, 0)), "max_bytes of small (100)");
+    assert_eq!(exceeded(usage.check(&config, "small", 0, 1)), "max_records of small (2)");
+
+    // a failed save gives its share back, a successful one counts what it stored
+    drop(first);
+    assert_eq!(used(&usage, "small"), (40, 1));
+    second.keep(30, 1);
+    assert_eq!(used(&usage, "small"), (30, 1));
+    assert!(usage.check(&config, "small", 70, 1).is_ok());
+    assert_eq!(used(&usage, "small"), (30, 1));
+  }
+
+  #[test]
+  fn streamed_bodies_grow_their_reservation() {
+    let (config, usage) = (config(), usage());
+    let mut streamed = usage.check(&config, "small", 0, 1).unwrap();
+    streamed.cover(60).unwrap();
+    streamed.cover(40).unwrap();
+    assert_eq!(used(&usage, "small"), (60, 1));
+    // another save only gets what is left
+    let other = usage.check(&config, "small", 30, 1).unwrap();
+    assert_eq!(streamed.cover(80).unwrap_err(), "max_bytes of small (100)");
+    streamed.cover(70).unwrap();
+    drop(other);
+    streamed.keep(70, 1);
+    assert_eq!(used(&usage, "small"), (70, 1));
+  }
+
+  #[test]
+  fn concurrent_saves_stay_within_the_quota() {
+    let (config, usage) = (config(), usage());
+    let kept = std::thread::scope(|scope| {
+      let saves: Vec<_> = (0..64)
+        .map(|_| {
+          scope.spawn(|| match usage.check(&config, "small", 10, 1) {
+            Ok(reservation) => {
+              reservation.keep(10, 1);
+              1
+            }
+            Err(_) => 0,
+          })
+        })
+        .collect();
+      saves.into_iter().map(|save| save.join().unwrap()).sum::<u64>()
+    });
+    assert_eq!(kept, 2);
+    assert_eq!(used(&usage, "small"), (20, 2));
+  }
+
+  #[test]
+  fn counters_match_storage_after_a_reload() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = FsStorage::open(dir.path(), Default::default()).unwrap();
+    let usage = Usage::load(&storage).unwrap();
+    let record = NewRecord::test("small", "127.0.0.1", chrono::Utc::now(), br#"{"a":1}"#);
+
+    let meta = storage.put(&record).unwrap();
+    add(&usage, "small", meta.size, 1);
+    // a day of appended lines is one record
+    for _ in 0..3 {
+      let appended = storage.append(&record).unwrap();
+      add(&usage, "small", appended.bytes, appended.created.into());
+    }
+    let counted = usage.report(&Config::default());
+
+    usage.reload(&storage).unwrap();
+    let reloaded = usage.report(&Config::default());
+    assert_eq!(counted[0].stats.records, 2);
+    assert_eq!(
+      (counted[0].stats.records, counted[0].stats.bytes),
+      (reloaded[0].stats.records, reloaded[0].stats.bytes)
+    );
+  }
+}
@@ -0,0 +1,288 @@
+//! token bucket rate limiting per client address, api key and name, configured in `[rate_limits]`
+
+use crate::{
+  auth::Identity,
+  client_ip::ClientIp,
+  config::{Config, RateLimit},
+  error::AppError,
+  AppState,
+};
+use axum::{
+  extract::{RawPathParams, Request, State},
+  http::HeaderValue,
+  middleware::Next,
+  response::Response,
+};
+use std::{
+  collections::HashMap,
+  net::IpAddr,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+/// full buckets are only looked for this often when a table is full, so a spray of new clients does not
+/// turn every request into a scan
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Bucket {
+  limit: RateLimit,
+  tokens: f64,
+  updated: Instant,
+}
+
+impl Bucket {
+  fn new(limit: RateLimit, now: Instant) -> Self {
+    Bucket {
+      limit,
+      tokens: limit.burst as f64,
+      updated: now,
+    }
+  }
+
+  fn refill(&mut self, now: Instant) {
+    let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst as f64);
+    self.updated = now;
+  }
+
+  /// seconds until the next request fits
+  fn wait(&self) -> f64 {
+    (1.0 - self.tokens).max(0.0) / self.limit.rate
+  }
+
+  /// seconds until the bucket is full again
+  fn reset(&self) -> f64 {
+    (self.limit.burst as f64 - self.tokens).max(0.0) / self.limit.rate
+  }
+}
+
+/// buckets of one kind, at most `capacity` of them
+struct Buckets {
+  map: HashMap<String, Bucket>,
+  /// shared by new keys while the map is full
+  overflow: Option<Bucket>,
+  capacity: usize,
+  swept: Instant,
+}
+
+impl Buckets {
+  fn new(capacity: usize, now: Instant) -> Self {
+    Buckets {
+      map: HashMap::new(),
+      overflow: None,
+      capacity,
+      swept: now,
+    }
+  }
+
+  fn get(&mut self, key: &str, limit: RateLimit, now: Instant) -> &mut Bucket {
+    if !self.map.contains_key(key) && self.map.len() >= self.capacity && now.saturating_duration_since(self.swept) >= SWEEP_INTERVAL {
+      // a full bucket is no different from a new one, so nothing is lost by dropping it
+      self.map.retain(|_, bucket| {
+        bucket.refill(now);
+        bucket.tokens < bucket.limit.burst as f64
+      });
+      self.swept = now;
+    }
+
+    if self.map.contains_key(key) || self.map.len() < self.capacity {
+      let bucket = self.map.entry(key.to_owned()).or_insert_with(|| Bucket::new(limit, now));
+      // the limit of a name may have been looked up differently when the bucket was made
+      bucket.limit = limit;
+      bucket
+    } else {
+      self.overflow.get_or_insert_with(|| Bucket::new(limit, now))
+    }
+  }
+}
+
+struct Inner {
+  ips: Buckets,
+  keys: Buckets,
+  names: Buckets,
+}
+
+/// the tightest bucket a request was counted against, reported in `RateLimit-*` headers
+#[derive(Clone, Copy)]
+struct Quota {
+  limit: u32,
+  remaining: u64,
+  reset: u64,
+}
+
+pub struct RateLimiter {
+  inner: Mutex<Inner>,
+}
+
+/// IPv6 clients usually get a whole /64, so they are counted per /64
+fn ip_key(ip: IpAddr) -> String {
+  match ip {
+    IpAddr::V4(ip) => ip.to_string(),
+    IpAddr::V6(ip) => {
+      let segments = ip.segments();
+      format!("{:x}:{:x}:{:x}:{:x}::/64", segments[0], segments[1], segments[2], segments[3])
+    }
+  }
+}
+
+impl RateLimiter {
+  pub fn new(config: &Config) -> Self {
+    let now = Instant::now();
+    let capacity = config.rate_limits.max_tracked;
+    RateLimiter {
+      inner: Mutex::new(Inner {
+        ips: Buckets::new(capacity, now),
+        keys: Buckets::new(capacity, now),
+        names: Buckets::new(capacity, now),
+      }),
+    }
+  }
+
+  fn inner(&self) -> std::sync::MutexGuard<'_, Inner> {
+    self.inner.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  /// takes a token from the bucket of the client address, `None` when there is no per-ip limit
+  fn check_ip(&self, config: &Config, ip: IpAddr) -> Result<Option<Quota>, AppError> {
+    let now = Instant::now();
+    let mut inner = self.inner();
+    let bucket = config.rate_limits.per_ip.map(|limit| inner.ips.get(&ip_key(ip), limit, now));
+    take(bucket.into_iter().collect(), now)
+  }
+
+  /// takes a token from the buckets of the api key and the name that apply, or none of them when one is empty.
+  /// `None` when no limit applies to the request
+  fn check(&self, config: &Config, key_id: Option<&str>, name: Option<&str>) -> Result<Option<Quota>, AppError> {
+    let now = Instant::now();
+    let mut inner = self.inner();
+    let Inner { keys, names, .. } = &mut *inner;
+
+    let mut buckets = vec![];
+    if let (Some(limit), Some(key_id)) = (config.rate_limits.per_key, key_id) {
+      buckets.push(keys.get(key_id, limit, now));
+    }
+    if let Some((limit, name)) = name.and_then(|name| Some((config.name_rate_limit(name)?, name))) {
+      buckets.push(names.get(name, limit, now));
+    }
+    take(buckets, now)
+  }
+}
+
+/// takes a token from every bucket, or none of them when one is empty
+fn take(mut buckets: Vec<&mut Bucket>, now: Instant) -> Result<Option<Quota>, AppError> {
+  for bucket in &mut buckets {
+    bucket.refill(now);
+  }
+  let wait = buckets.iter().map(|bucket| bucket.wait()).fold(0.0, f64::max);
+  if wait > 0.0 {
+    return Err(AppError::RateLimited(wait.ceil() as u64));
+  }
+
+  for bucket in &mut buckets {
+    bucket.tokens -= 1.0;
+  }
+  let tightest = buckets.iter().min_by(|a, b| a.tokens.total_cmp(&b.tokens));
+  Ok(tightest.map(|bucket| Quota {
+    limit: bucket.limit.burst,
+    remaining: bucket.tokens.max(0.0).floor() as u64,
+    reset: bucket.reset().ceil() as u64,
+  }))
+}
+
+/// middleware in front of authentication, so clients sending bad keys are limited per address too
+pub async fn limit_ip(
+  State(state): State<Arc<AppState>>,
+  ClientIp { ip, .. }: ClientIp,
+  req: Request,
+  next: Next,
+) -> Result<Response, AppError> {
+  let quota = state.rate_limiter.check_ip(&state.config, ip)?;
+  let mut response = next.run(req).await;
+  // `limit` leaves its quota on the response when it ran, the headers report the tighter one
+  let inner = response.extensions_mut().remove::<Quota>();
+  let tightest = [quota, inner].into_iter().flatten().min_by_key(|quota| quota.remaining);
+  if let Some(quota) = tightest {
+    let headers = response.headers_mut();
+    headers.insert("ratelimit-limit", HeaderValue::from(quota.limit));
+    headers.insert("ratelimit-remaining", HeaderValue::from(quota.remaining));
+    headers.insert("ratelimit-reset", HeaderValue::from(quota.reset));
+  }
+  Ok(response)
+}
+
+/// middleware behind authentication, limits per api key and name, 429 with `Retry-After` when a limit is exceeded
+pub async fn limit(State(state): State<Arc<AppState>>, params: RawPathParams, req: Request, next: Next) -> Result<Response, AppError> {
+  let name = params.iter().find(|(key, _)| *key == "name").map(|(_, value)| value);
+  let key_id = req.extensions().get::<Identity>().map(|identity| identity.key_id.clone());
+  let quota = state.rate_limiter.check(&state.config, key_id.as_deref(), name)?;
+  let mut response = next.run(req).await;
+  if let Some(quota) = quota {
+    response.extensions_mut().insert(quota);
+  }
+  Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const LIMIT: RateLimit = RateLimit { rate: 2.0, burst: 3 };
+
+  #[test]
+  fn bucket_empties_and_refills() {
+    let start = Instant::now();
+    let mut bucket = Bucket::new(LIMIT, start);
+    for remaining in [2, 1, 0] {
+      assert_eq!(take(vec![&mut bucket], start).unwrap().unwrap().remaining, remaining);
+    }
+    assert!(matches!(take(vec![&mut bucket], start), Err(AppError::RateLimited(1))));
+
+    // two tokens a second
+    let later = start + Duration::from_millis(500);
+    assert!(take(vec![&mut bucket], later).is_ok());
+    assert!(take(vec![&mut bucket], later).is_err());
+    bucket.refill(start + Duration::from_secs(60));
+    assert_eq!(bucket.tokens, 3.0);
+  }
+
+  #[test]
+  fn nothing_is_taken_when_one_bucket_is_empty() {
+    let now = Instant::now();
+    let mut full = Bucket::new(LIMIT, now);
+    let mut empty = Bucket::new(LIMIT, now);
+    empty.tokens = 0.0;
+    assert!(take(vec![&mut full, &mut empty], now).is_err());
+    assert_eq!(full.tokens, 3.0);
+  }
+
+  #[test]
+  fn quota_reports_the_tightest_bucket() {
+    let now = Instant::now();
+    let mut loose = Bucket::new(RateLimit { rate: 1.0, burst: 100 }, now);
+    let mut tight = Bucket::new(LIMIT, now);
+    let quota = take(vec![&mut loose, &mut tight], now).unwrap().unwrap();
+    assert_eq!((quota.limit, quota.remaining, quota.reset), (3, 2, 1));
+  }
+
+  #[test]
+  fn new_keys_share_the_overflow_bucket_while_full() {
+    let now = Instant::now();
+    let mut buckets = Buckets::new(1, now);
+    buckets.get("a", LIMIT, now).tokens = 0.0;
+    buckets.get("b", LIMIT, now).tokens -= 1.0;
+    assert_eq!(buckets.get("c", LIMIT, now).tokens, 2.0);
+    assert_eq!(buckets.map.len(), 1);
+
+    // once "a" is full again it is swept and the next new key gets its own bucket
+    let later = now + Duration::from_secs(10);
+    buckets.get("d", LIMIT, later);
+    assert!(buckets.map.contains_key("d") && !buckets.map.contains_key("a"));
+  }
+
+  #[test]
+  fn ipv6_clients_are_counted_per_64() {
+    assert_eq!(ip_key("203.0.113.9".parse().unwrap()), "203.0.113.9");
+    assert_eq!(ip_key("2001:db8:1:2:aaaa::1".parse().unwrap()), "2001:db8:1:2::/64");
+    assert_eq!(ip_key("2001:db8:1:2:bbbb::2".parse().unwrap()), "2001:db8:1:2::/64");
+  }
+}
@@ -0,0 +1,99 @@
+//! background task removing records older than the `max_age` of their name, every `retention_interval` seconds
+
+use crate::{
+  config::OnExpiry,
+  durable,
+  storage::{BodyKind, RecordMeta},
+  AppState,
+};
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use std::{fs, io, sync::Arc, time::Duration};
+use tracing::{error, info};
+
+pub fn spawn(state: Arc<AppState>) {
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(state.config.retention_interval.max(1)));
+    loop {
+      interval.tick().await;
+      let state = state.clone();
+      // storage calls block
+      match tokio::task::spawn_blocking(move || sweep(&state)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => error!("Retention failed: {}", e),
+        Err(e) => error!("Retention failed: {}", e),
+      }
+    }
+  });
+}
+
+/// when a record was received, files saved before records got unique ids only tell the day.
+/// an append log counts from the end of its day, so it only expires once its last line has
+fn received_at(meta: &RecordMeta) -> Option<DateTime<Utc>> {
+  if let Some(time) = &meta.received_at {
+    return DateTime::parse_from_rfc3339(time).ok().map(|time| time.to_utc());
+  }
+  let mut day = NaiveDate::parse_from_str(&meta.date, "%Y-%m-%d").ok()?;
+  if meta.kind == BodyKind::Lines {
+    day = day.succ_opt()?;
+  }
+  day.and_hms_opt(0, 0, 0).map(|time| time.and_utc())
+}
+
+/// one pass over every name with a `max_age`, then reloads the usage counters from storage
+fn sweep(state: &AppState) -> io::Result<()> {
+  let now = Utc::now();
+  for (name, name_config) in &state.config.names {
+    let Some(cutoff) = name_config
+      .max_age
+      .and_then(|max_age| TimeDelta::try_seconds(max_age.try_into().ok()?))
+      .and_then(|max_age| now.checked_sub_signed(max_age))
+    else {
+      continue;
+    };
+
+    let mut expired = 0;
+    for meta in state.storage.list(name)? {
+      if received_at(&meta).is_none_or(|received_at| received_at >= cutoff) {
+        continue;
+      }
+      if name_config.on_expiry == OnExpiry::Archive {
+        archive(state, name, &meta)?;
+      }
+      if let Some(bytes) = state.storage.delete(name, &meta.id)? {
+        state.usage.remove(name, bytes);
+        state.dedup.forget(name, &meta.id);
+        expired += 1;
+      }
+    }
+    if expired > 0 {
+      let action = match name_config.on_expiry {
+        OnExpiry::Delete => "Deleted",
+        OnExpiry::Archive => "Archived",
+      };
+      info!(name, expired, "{} expired records", action);
+    }
+  }
+
+  state.usage.reload(state.storage.as_ref())
+}
+
+/// copies a record and its metadata to `{archive_dir}/{name}/{date}/`, before it is deleted from storage
+fn archive(state: &AppState, name: &str, meta: &RecordMeta) -> io::Result<()> {
+  let Some((kind, body)) = state.storage.get(name, &meta.id)? else {
+    return Ok(());
+  };
+  let dir = state.config.archive_dir().join(name).join(&meta.date);
+  fs::create_dir_all(&dir)?;
+
+  let extension = match kind {
+    BodyKind::Json => "json",
+    BodyKind::Binary => "bin",
+    BodyKind::Lines => "jsonl",
+  };
+  durable::write_atomic(&dir, &format!("{}.{}", meta.id, extension), &mut &body[..])?;
+  if let Some(metadata) = state.storage.metadata(name, &meta.id)? {
+    let sidecar = serde_json::to_vec_pretty(&metadata)?;
+    durable::write_atomic(&dir, &format!("{}.meta.json", meta.id), &mut &sidecar[..])?;
+  }
+  Ok(())
+}
@@ -0,0 +1,81 @@
+use jsonschema::Validator;
+use serde::Serialize;
+use serde_json::Value;
+use std::{collections::HashMap, fs, io, path::Path};
+
+/// compiled JSON Schemas by data name, loaded from `{schemas_dir}/{name}.json`
+#[derive(Default)]
+pub struct Schemas {
+  validators: HashMap<String, Validator>,
+}
+
+/// one place where a payload does not match its schema
+#[derive(Serialize, Debug)]
+pub struct Violation {
+  /// JSON pointer into the payload, empty for the document itself
+  pub path: String,
+  pub message: String,
+}
+
+impl Schemas {
+  /// a missing directory means no schemas; an unreadable or invalid schema file is an error
+  pub fn load(dir: &Path) -> Result<Schemas, String> {
+    let entries = match fs::read_dir(dir) {
+      Ok(entries) => entries,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Schemas::default()),
+      Err(e) => return Err(format!("failed to read schemas directory {}: {}", dir.display(), e)),
+    };
+
+    let mut validators = HashMap::new();
+    for entry in entries {
+      let path = entry
+        .map_err(|e| format!("failed to read schemas directory {}: {}", dir.display(), e))?
+        .path();
+      if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+        continue;
+      }
+      let Some(name) = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .filter(|name| crate::is_valid_name(name))
+      else {
+        continue;
+      };
+
+      let content = fs::read_to_string(&path).map_err(|e| format!("failed to read schema {}: {}", path.display(), e))?;
+      let schema: Value = serde_json::from_str(&content).map_err(|e| format!("schema {} is not JSON: {}", path.display(), e))?;
+      let validator = jsonschema::validator_for(&schema).map_err(|e| format!("invalid schema {}: {}", path.display(), e))?;
+      validators.insert(name.to_owned(), validator);
+    }
+
+    Ok(Schemas { validators })
+  }
+
+  pub fn len(&self) -> usize {
+    self.validators.len()
+  }
+
+  pub fn contains(&self, name: &str) -> bool {
+    self.validators.contains_key(name)
+  }
+
+  /// checks `payload` against the schema of `name`, names without a schema accept anything
+  pub fn validate(&self, name: &str, payload: &Value) -> Result<(), Vec<Violation>> {
+    let Some(validator) = self.validators.get(name) else {
+      return Ok(());
+    };
+
+    let violations: Vec<Violation> = validator
+      .iter_errors(payload)
+      .map(|e| Violation {
+        path: e.instance_path.to_string(),
+        message: e.to_string(),
+      })
+      .collect();
+    if violations.is_empty() {
+      Ok(())
+    } else {
+      Err(violations)
+    }
+  }
+}
@@ -0,0 +1,28 @@
+//! stopping on SIGINT or SIGTERM
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal as unix_signal, SignalKind};
+
+/// resolves with the name of the first stop signal received
+pub async fn signal() -> &'static str {
+  let interrupt = async {
+    tokio::signal::ctrl_c().await.expect("failed to listen for SIGINT");
+    "SIGINT"
+  };
+
+  #[cfg(unix)]
+  let terminate = async {
+    unix_signal(SignalKind::terminate())
+      .expect("failed to listen for SIGTERM")
+      .recv()
+      .await;
+    "SIGTERM"
+  };
+  #[cfg(not(unix))]
+  let terminate = std::future::pending::<&'static str>();
+
+  tokio::select! {
+    name = interrupt => name,
+    name = terminate => name,
+  }
+}
@@ -0,0 +1,555 @@
+use super::{
+  format_time, generate_id, next_seq, sha256_hex, Appended, BodyKind, HashingReader, Metadata, NameStats, NewRecord, RecordMeta,
+  Storage,
+};
+use crate::{
+  append::{self, Appenders},
+  config::Compression,
+  durable::{self, TempFile},
+};
+use flate2::read::{GzDecoder, GzEncoder};
+use std::{
+  collections::BTreeMap,
+  fs,
+  io::{self, Read},
+  net::{Ipv4Addr, Ipv6Addr},
+  path::{Path, PathBuf},
+};
+
+/// one file per record in a flat directory, named after the record id: `{id}.json` for JSON documents and
+/// `{id}.bin` for binary bodies, each with its metadata in a `{id}.meta.json` sidecar. daily append logs are records
+/// too, `{name}-{date}.jsonl` with the metadata in every line. compressed records get `.gz` or `.zst` on top, sidecars
+/// and append logs are never compressed
+pub struct FsStorage {
+  dir: PathBuf,
+  appenders: Appenders,
+  /// used for new records, existing ones are read with whatever they were written with
+  compression: Compression,
+}
+
+fn extension(kind: BodyKind) -> &'static str {
+  match kind {
+    BodyKind::Json => "json",
+    BodyKind::Binary => "bin",
+    BodyKind::Lines => "jsonl",
+  }
+}
+
+const COMPRESSIONS: [Compression; 3] = [Compression::None, Compression::Gzip, Compression::Zstd];
+
+fn compression_suffix(compression: Compression) -> &'static str {
+  match compression {
+    Compression::None => "",
+    Compression::Gzip => ".gz",
+    Compression::Zstd => ".zst",
+  }
+}
+
+fn record_filename(id: &str, kind: BodyKind, compression: Compression) -> String {
+  format!("{}.{}{}", id, extension(kind), compression_suffix(compression))
+}
+
+/// strips `.gz` or `.zst` off a filename
+fn split_compression(filename: &str) -> (&str, Compression) {
+  COMPRESSIONS
+    .iter()
+    .skip(1)
+    .find_map(|&c| Some((filename.strip_suffix(compression_suffix(c))?, c)))
+    .unwrap_or((filename, Compression::None))
+}
+
+fn decompress(compression: Compression, stored: Vec<u8>) -> io::Result<Vec<u8>> {
+  match compression {
+    Compression::None => Ok(stored),
+    Compression::Gzip => {
+      let mut body = vec![];
+      GzDecoder::new(&stored[..]).read_to_end(&mut body)?;
+      Ok(body)
+    }
+    Compression::Zstd => zstd::decode_all(&stored[..]),
+  }
+}
+
+fn sidecar_filename(id: &str) -> String {
+  format!("{}.meta.json", id)
+}
+
+impl FsStorage {
+  /// also sweeps temp files left by a previous run that was killed mid-write
+  pub fn open(dir: &Path, compression: Compression) -> io::Result<FsStorage> {
+    fs::create_dir_all(dir)?;
+    match durable::sweep_temp_files(dir)? {
+      0 => {}
+      removed => tracing::info!("Removed {} stale temp files from {}", removed, dir.display()),
+    }
+
+    Ok(FsStorage {
+      dir: dir.to_owned(),
+      appenders: Appenders::default(),
+      compression,
+    })
+  }
+
+  /// creates the directory again if it was removed after startup
+  fn ensure_dir(&self) -> io::Result<()> {
+    if !self.dir.exists() {
+      fs::create_dir_all(&self.dir)?;
+    }
+    Ok(())
+  }
+
+  /// bytes of the body in the record file `filename` of `len` bytes, the same whether it is compressed or not:
+  /// compressed records are measured by their sidecar, or decompressed when they have none
+  fn body_size(&self, filename: &str, len: u64) -> io::Result<u64> {
+    let (stem, compression) = split_compression(filename);
+    if compression == Compression::None {
+      return Ok(len);
+    }
+    let id = stem.rsplit_once('.').map_or(stem, |(id, _)| id);
+    match fs::read(self.dir.join(sidecar_filename(id))) {
+      Ok(sidecar) => {
+        if let Ok(Metadata { size, sha256, .. }) = serde_json::from_slice(&sidecar) {
+          // sidecars without a hash are older than the size field
+          if !sha256.is_empty() {
+            return Ok(size);
+          }
+        }
+      }
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+      Err(e) => return Err(e),
+    }
+    Ok(decompress(compression, fs::read(self.dir.join(filename))?)?.len() as u64)
+  }
+
+  /// the file holding record `id` of `name`, whichever kind and compression it is
+  fn find(&self, name: &str, id: &str) -> io::Result<Option<(RecordFile, String)>> {
+    for kind in [BodyKind::Json, BodyKind::Binary, BodyKind::Lines] {
+      for compression in COMPRESSIONS {
+        let filename = record_filename(id, kind, compression);
+        if let Some(parsed) = parse_filename(name, &filename) {
+          if self.dir.join(&filename).try_exists()? {
+            return Ok(Some((parsed, filename)));
+          }
+        }
+      }
+    }
+    Ok(None)
+  }
+}
+
+impl Storage for FsStorage {
+  fn put(&self, record: &NewRecord) -> io::Result<RecordMeta> {
+    self.put_reader(record, &mut &record.body[..])
+  }
+
+  fn put_reader(&self, record: &NewRecord, body: &mut dyn Read) -> io::Result<RecordMeta> {
+    self.ensure_dir()?;
+
+    let mut body = HashingReader::new(body);
+    let (temp, _) = match self.compression {
+      Compression::None => TempFile::write(&self.dir, &mut body)?,
+      Compression::Gzip => TempFile::write(&self.dir, &mut GzEncoder::new(&mut body, flate2::Compression::default()))?,
+      Compression::Zstd => TempFile::write(&self.dir, &mut zstd::stream::read::Encoder::new(&mut body, 0)?)?,
+    };
+    let (body_size, sha256) = body.finish();
+    let sidecar = serde_json::to_vec_pretty(&record.metadata(body_size, sha256))?;
+
+    // a fresh sequence number is taken whenever the id is already in use. the record and its sidecar are linked
+    // into place without replacing anything, so another process writing to the same directory cannot overwrite them
+    let filename = loop {
+      let id = generate_id(&record.name, &record.addr, record.received_at, next_seq());
+      if self.find(&record.name, &id)?.is_some() {
+        continue;
+      }
+      let filename = record_filename(&id, record.kind, self.compression);
+      match temp.link(&filename) {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+        result => result?,
+      }
+      match durable::write_new(&self.dir, &sidecar_filename(&id), &mut &sidecar[..]) {
+        Ok(_) => break filename,
+        Err(e) => {
+          // the request fails or takes another id, so the record should not show up under this one
+          let _ = fs::remove_file(self.dir.join(&filename));
+          if e.kind() != io::ErrorKind::AlreadyExists {
+            return Err(e);
+          }
+        }
+      }
+    };
+    drop(temp);
+
+    let parsed = parse_filename(&record.name, &filename).expect("generated filename parses back");
+    Ok(parsed.into_meta(filename, body_size))
+  }
+
+  fn append_usage(&self, record: &NewRecord) -> io::Result<(u64, u64)> {
+    let exists = self.dir.join(append::log_filename(record)).try_exists()?;
+    Ok((append::line(record)?.len() as u64, if exists { 0 } else { 1 }))
+  }
+
+  /// the response describes the line that was added, `GET /data/{name}/{id}` serves the whole log
+  fn append(&self, record: &NewRecord) -> io::Result<Appended> {
+    self.ensure_dir()?;
+
+    let (filename, bytes, created) = self.appenders.append(&self.dir, record)?;
+    Ok(Appended {
+      meta: RecordMeta {
+        id: filename.trim_end_matches(".jsonl").to_owned(),
+        filename: Some(filename),
+        date: record.received_at.format("%Y-%m-%d").to_string(),
+        addr: record.addr.clone(),
+        received_at: Some(format_time(record.received_at)),
+        kind: BodyKind::Lines,
+        size: bytes,
+      },
+      bytes,
+      created,
+    })
+  }
+
+  fn get(&self, name: &str, id: &str) -> io::Result<Option<(BodyKind, Vec<u8>)>> {
+    let Some((parsed, filename)) = self.find(name, id)? else {
+      return Ok(None);
+    };
+
+    match fs::read(self.dir.join(&filename)) {
+      Ok(stored) => Ok(Some((parsed.kind, decompress(parsed.compression, stored)?))),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e),
+    }
+  }
+
+  fn metadata(&self, name: &str, id: &str) -> io::Result<Option<Metadata>> {
+    let Some((parsed, _)) = self.find(name, id)? else {
+      return Ok(None);
+    };
+
+    let mut metadata: Metadata = match fs::read(self.dir.join(sidecar_filename(id))) {
+      Ok(sidecar) => serde_json::from_slice(&sidecar)?,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Metadata::default(),
+      Err(e) => return Err(e),
+    };
+    // older records have no sidecar, or one without a hash, what the filename does not tell is recomputed from the body
+    if metadata.sha256.is_empty() {
+      let Some((_, body)) = self.get(name, id)? else {
+        return Ok(None);
+      };
+      metadata.size = body.len() as u64;
+      metadata.sha256 = sha256_hex(&body);
+    }
+    if metadata.received_at.is_none() {
+      metadata.received_at = parsed.received_at;
+    }
+    if metadata.addr.is_empty() {
+      metadata.addr = parsed.addr;
+    }
+    metadata.kind = parsed.kind;
+    Ok(Some(metadata))
+  }
+
+  fn list(&self, name: &str) -> io::Result<Vec<RecordMeta>> {
+    let entries = match fs::read_dir(&self.dir) {
+      Ok(entries) => entries,
+      // nothing saved yet
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+      Err(e) => return Err(e),
+    };
+
+    let mut records = vec![];
+    for entry in entries.flatten() {
+      let filename = entry.file_name().to_string_lossy().into_owned();
+      let Some(parsed) = parse_filename(name, &filename) else {
+        continue;
+      };
+      let len = entry.metadata().map(|m| m.len()).unwrap_or(0);
+      let size = self.body_size(&filename, len).unwrap_or(len);
+      records.push(parsed.into_meta(filename, size));
+    }
+    records.sort_by(|a, b| a.id.cmp(&b.id));
+
+    Ok(records)
+  }
+
+  fn delete(&self, name: &str, id: &str) -> io::Result<Option<u64>> {
+    let Some((parsed, filename)) = self.find(name, id)? else {
+      return Ok(None);
+    };
+
+    let path = self.dir.join(&filename);
+    let size = match fs::metadata(&path).and_then(|m| self.body_size(&filename, m.len())) {
+      Ok(size) => size,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e),
+    };
+    let removed = match parsed.kind {
+      // a log may still be open for appending
+      BodyKind::Lines => self.appenders.remove(&path),
+      _ => fs::remove_file(&path),
+    };
+    match removed {
+      Ok(()) => {}
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e),
+    }
+    match fs::remove_file(self.dir.join(sidecar_filename(id))) {
+      Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+      _ => {}
+    }
+    durable::sync_dir(&self.dir)?;
+    Ok(Some(size))
+  }
+
+  fn stats(&self) -> io::Result<Vec<NameStats>> {
+    let entries = match fs::read_dir(&self.dir) {
+      Ok(entries) => entries,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+      Err(e) => return Err(e),
+    };
+
+    let mut by_name: BTreeMap<String, NameStats> = BTreeMap::new();
+    for entry in entries.flatten() {
+      let filename = entry.file_name().to_string_lossy().into_owned();
+      let Some(name) = name_of(&filename) else {
+        continue;
+      };
+      let len = entry.metadata().map(|m| m.len()).unwrap_or(0);
+      let size = self.body_size(&filename, len).unwrap_or(len);
+      let stats = by_name.entry(name.to_owned()).or_insert_with(|| NameStats {
+        name: name.to_owned(),
+        ..NameStats::default()
+      });
+      stats.records += 1;
+      stats.bytes += size;
+    }
+
+    Ok(by_name.into_values().collect())
+  }
+
+  fn flush(&self) -> io::Result<()> {
+    self.appenders.sync()
+  }
+}
+
+/// a record on disk, parsed back from a filename built from `generate_id`
+struct RecordFile {
+  id: String,
+  date: String,
+  addr: String,
+  received_at: Option<String>,
+  kind: BodyKind,
+  compression: Compression,
+}
+
+impl RecordFile {
+  fn into_meta(self, filename: String, size: u64) -> RecordMeta {
+    RecordMeta {
+      id: self.id,
+      filename: Some(filename),
+      date: self.date,
+      addr: self.addr,
+      received_at: self.received_at,
+      kind: self.kind,
+      size,
+    }
+  }
+}
+
+/// parses `{name}-{YYYY-MM-DD}-{addr}-{HHMMSSmmm}-{seq}.json` (or `.bin`, either possibly compressed) as well as the older
+/// `{name}-{YYYY-MM-DD}-{addr}.json` and append logs `{name}-{YYYY-MM-DD}.jsonl`, returns `None` for files of other
+/// names and for sidecars
+fn parse_filename(name: &str, filename: &str) -> Option<RecordFile> {
+  let (filename, compression) = split_compression(filename);
+  let (id, kind) = [(".json", BodyKind::Json), (".bin", BodyKind::Binary), (".jsonl", BodyKind::Lines)]
+    .into_iter()
+    .find_map(|(extension, kind)| Some((filename.strip_suffix(extension)?, kind)))?;
+  // addresses are stored with `.` replaced, so a dot means this is a sidecar like `{id}.meta.json`
+  if id.contains('.') {
+    return None;
+  }
+  let rest = id.strip_prefix(name)?.strip_prefix('-')?;
+  let date = rest.get(..10)?;
+  let day = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+  if kind == BodyKind::Lines {
+    // a log holds lines from many clients over the day
+    return (rest.len() == 10 && compression == Compression::None).then(|| RecordFile {
+      id: id.to_owned(),
+      date: date.to_owned(),
+      addr: String::new(),
+      received_at: None,
+      kind,
+      compression,
+    });
+  }
+  let tail = rest[10..].strip_prefix('-')?;
+
+  let (addr, received_at) = match split_time_suffix(tail) {
+    Some((addr, time)) => (addr, Some(format_time(day.and_time(time).and_utc()))),
+    None => (tail, None),
+  };
+
+  Some(RecordFile {
+    id: id.to_owned(),
+    date: date.to_owned(),
+    addr: unescape_addr(addr),
+    received_at,
+    kind,
+    compression,
+  })
+}
+
+/// the client address `generate_id` put in a filename with `.` and `:` replaced by `_`, as it was given when it
+/// parses back as an IP address
+fn unescape_addr(addr: &str) -> String {
+  let v4 = addr.replace('_', ".");
+  if v4.parse::<Ipv4Addr>().is_ok() {
+    return v4;
+  }
+  let v6 = addr.replace('_', ":");
+  if v6.parse::<Ipv6Addr>().is_ok() {
+    return v6;
+  }
+  addr.to_owned()
+}
+
+/// splits `{addr}-{HHMMSSmmm}-{seq}` into the address and the receive time
+fn split_time_suffix(tail: &str) -> Option<(&str, chrono::NaiveTime)> {
+  // "-HHMMSSmmm-ssss"
+  let split = tail.len().checked_sub(15)?;
+  let (addr, suffix) = (tail.get(..split)?, tail.get(split..)?);
+  let bytes = suffix.as_bytes();
+  if bytes[0] != b'-' || bytes[10] != b'-' || !bytes[1..10].iter().chain(&bytes[11..]).all(u8::is_ascii_digit) {
+    return None;
+  }
+  let time = chrono::NaiveTime::parse_from_str(&suffix[1..10], "%H%M%S%3f").ok()?;
+  Some((addr, time))
+}
+
+/// name of a `.json` or `.bin` record or a `.jsonl` log, the name ends right before the first `-{YYYY-MM-DD}` that follows it
+fn name_of(filename: &str) -> Option<&str> {
+  let (filename, _) = split_compression(filename);
+  let stem = [".json", ".bin", ".jsonl"].iter().find_map(|ext| filename.strip_suffix(ext))?;
+  if stem.contains('.') {
+    return None;
+  }
+  stem
+    .match_indices('-')
+    .map(|(i, _)| i)
+    .find(|&i| {
+      let date = stem.get(i + 1..i + 11);
+      let after = stem.get(i + 11..i + 12);
+      date.is_some_and(|d| chrono::NaiveDate::parse_from_str(d, "%Y-%m-%d").is_ok()) && matches!(after, None | Some("-"))
+    })
+    .map(|i| &stem[..i])
+    .filter(|name| !name.is_empty() && crate::is_valid_name(name))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::{TimeZone, Utc};
+
+  fn record(addr: &str, body: &[u8]) -> NewRecord {
+    NewRecord::test("lab", addr, Utc.with_ymd_and_hms(2024, 5, 6, 7, 8, 9).unwrap(), body)
+  }
+
+  #[test]
+  fn filenames_parse_back() {
+    let parsed = parse_filename("lab", "lab-2024-05-06-203_0_113_9-070809123-0042.json.gz").unwrap();
+    assert_eq!(parsed.id, "lab-2024-05-06-203_0_113_9-070809123-0042");
+    assert_eq!(parsed.date, "2024-05-06");
+    assert_eq!(parsed.addr, "203.0.113.9");
+    assert_eq!(parsed.received_at.as_deref(), Some("2024-05-06T07:08:09.123Z"));
+    assert_eq!((parsed.kind, parsed.compression), (BodyKind::Json, Compression::Gzip));
+
+    let parsed = parse_filename("lab", "lab-2024-05-06-2001_db8__1-235959999-0000.bin.zst").unwrap();
+    assert_eq!(parsed.addr, "2001:db8::1");
+    assert_eq!((parsed.kind, parsed.compression), (BodyKind::Binary, Compression::Zstd));
+
+    // before records got unique ids
+    let parsed = parse_filename("lab", "lab-2024-05-06-127_0_0_1.json").unwrap();
+    assert_eq!((parsed.addr.as_str(), parsed.received_at), ("127.0.0.1", None));
+
+    let parsed = parse_filename("lab", "lab-2024-05-06.jsonl").unwrap();
+    assert_eq!(
+      (parsed.id.as_str(), parsed.kind, parsed.addr.as_str()),
+      ("lab-2024-05-06", BodyKind::Lines, "")
+    );
+  }
+
+  #[test]
+  fn other_files_do_not_parse() {
+    for filename in [
+      "lab-2024-05-06-127_0_0_1-070809123-0000.meta.json",
+      "lab-2-2024-05-06-127_0_0_1-070809123-0000.json",
+      "labs-2024-05-06-127_0_0_1.json",
+      "lab-2024-13-06-127_0_0_1.json",
+      "lab-2024-05-06-127_0_0_1.txt",
+      "lab-2024-05-06.jsonl.gz",
+      "lab-2024-05-06-extra.jsonl",
+      ".lab-2024-05-06-127_0_0_1.json.tmp",
+      "lab-2024-05-06.json",
+    ] {
+      assert!(parse_filename("lab", filename).is_none(), "{}", filename);
+    }
+  }
+
+  #[test]
+  fn time_suffixes_are_split_off() {
+    let (addr, time) = split_time_suffix("10_0_0_1-070809123-0001").unwrap();
+    assert_eq!((addr, time.to_string()), ("10_0_0_1", "07:08:09.123".to_owned()));
+    // legacy ids end with the address
+    assert!(split_time_suffix("10_0_0_1").is_none());
+    assert!(split_time_suffix("10_0_0_1-07080912x-0001").is_none());
+    assert!(split_time_suffix("10_0_0_1-250809123-0001").is_none());
+    assert!(split_time_suffix("x-070809123_0001").is_none());
+    assert!(split_time_suffix("é-070809123-000é").is_none());
+  }
+
+  #[test]
+  fn names_are_found_before_the_date() {
+    assert_eq!(name_of("lab-2024-05-06-127_0_0_1-070809123-0000.json"), Some("lab"));
+    assert_eq!(name_of("lab-2-2024-05-06-127_0_0_1.bin.gz"), Some("lab-2"));
+    assert_eq!(name_of("lab-2024-05-06.jsonl"), Some("lab"));
+    assert_eq!(name_of("lab-2024-05-06-127_0_0_1.meta.json"), None);
+    assert_eq!(name_of("-2024-05-06.jsonl"), None);
+    assert_eq!(name_of("lab-2024-05-06x.json"), None);
+    assert_eq!(name_of(".webhooks.jsonl"), None);
+  }
+
+  #[test]
+  fn addresses_are_unescaped() {
+    assert_eq!(unescape_addr("203_0_113_9"), "203.0.113.9");
+    assert_eq!(unescape_addr("2001_db8__1"), "2001:db8::1");
+    assert_eq!(unescape_addr("__1"), "::1");
+    assert_eq!(unescape_addr("unknown"), "unknown");
+  }
+
+  #[test]
+  fn listings_report_the_address_and_the_uncompressed_size() {
+    let body = vec![b' '; 10000];
+    for compression in COMPRESSIONS {
+      let dir = tempfile::tempdir().unwrap();
+      let storage = FsStorage::open(dir.path(), compression).unwrap();
+      let saved = storage.put(&record("2001:db8::1", &body)).unwrap();
+      assert_eq!(saved.size, 10000);
+
+      let listed = storage.list("lab").unwrap();
+      assert_eq!(listed.len(), 1);
+      assert_eq!((listed[0].addr.as_str(), listed[0].size), ("2001:db8::1", 10000));
+      assert_eq!(storage.stats().unwrap()[0].bytes, 10000);
+      assert_eq!(storage.metadata("lab", &saved.id).unwrap().unwrap().addr, "2001:db8::1");
+      assert_eq!(storage.delete("lab", &saved.id).unwrap(), Some(10000));
+    }
+  }
+
+  #[test]
+  fn compressed_records_without_a_sidecar_are_measured_decompressed() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = FsStorage::open(dir.path(), Compression::Zstd).unwrap();
+    let saved = storage.put(&record("203.0.113.9", b"[1, 2, 3]")).unwrap();
+    fs::remove_file(dir.path().join(sidecar_filename(&saved.id))).unwrap();
+    assert_eq!(storage.list("lab").unwrap()[0].size, 9);
+    assert_eq!(storage.list("lab").unwrap()[0].addr, "203.0.113.9");
+  }
+}
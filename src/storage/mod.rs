@@ -0,0 +1,267 @@
+//! persistence behind the `/data` routes, the backend is picked by `[storage] backend` in the config
+
+mod fs;
+mod sqlite;
+
+use crate::config::{Config, StorageBackend};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+  collections::BTreeMap,
+  io::{self, Read},
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
+};
+
+pub use self::fs::FsStorage;
+pub use self::sqlite::SqliteStorage;
+
+/// what the body of a record holds
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyKind {
+  /// a JSON document
+  #[default]
+  Json,
+  /// opaque bytes posted as `application/octet-stream`
+  Binary,
+  /// a daily append log, one JSON envelope per line
+  Lines,
+}
+
+impl BodyKind {
+  pub fn content_type(self) -> &'static str {
+    match self {
+      BodyKind::Json => "application/json",
+      BodyKind::Binary => "application/octet-stream",
+      BodyKind::Lines => "application/x-ndjson",
+    }
+  }
+}
+
+/// a payload accepted by `save_data`, not stored yet
+#[derive(Clone)]
+pub struct NewRecord {
+  pub name: String,
+  /// resolved client address
+  pub addr: String,
+  /// socket address of the connection
+  pub peer: String,
+  /// subject of the verified TLS client certificate
+  pub client_subject: Option<String>,
+  pub received_at: DateTime<Utc>,
+  /// the headers from `metadata_headers` that were sent
+  pub headers: BTreeMap<String, String>,
+  pub kind: BodyKind,
+  pub body: Vec<u8>,
+}
+
+impl NewRecord {
+  /// `size` and `sha256` describe the body as stored, which may have been streamed instead of being in `body`
+  pub fn metadata(&self, size: u64, sha256: String) -> Metadata {
+    Metadata {
+      received_at: Some(format_time(self.received_at)),
+      addr: self.addr.clone(),
+      peer: Some(self.peer.clone()),
+      client_subject: self.client_subject.clone(),
+      kind: self.kind,
+      size,
+      sha256,
+      headers: self.headers.clone(),
+    }
+  }
+}
+
+#[cfg(test)]
+impl NewRecord {
+  /// a JSON record sent without metadata headers or a client certificate
+  pub fn test(name: &str, addr: &str, received_at: DateTime<Utc>, body: &[u8]) -> NewRecord {
+    NewRecord {
+      name: name.to_owned(),
+      addr: addr.to_owned(),
+      peer: "127.0.0.1:1234".to_owned(),
+      client_subject: None,
+      received_at,
+      headers: BTreeMap::new(),
+      kind: BodyKind::Json,
+      body: body.to_vec(),
+    }
+  }
+}
+
+/// context of a submission kept next to its body, served by `/data/{name}/{id}/meta`
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct Metadata {
+  /// RFC 3339 with milliseconds, missing for files saved before records got unique ids
+  pub received_at: Option<String>,
+  /// resolved client address
+  pub addr: String,
+  /// socket address of the connection, the proxy when the client was behind one. missing for older records
+  pub peer: Option<String>,
+  /// subject of the TLS client certificate the connection was authenticated with, when mutual TLS is on
+  pub client_subject: Option<String>,
+  pub kind: BodyKind,
+  /// bytes of the body before any compression at rest
+  pub size: u64,
+  /// hex encoded SHA-256 of the same bytes
+  pub sha256: String,
+  pub headers: BTreeMap<String, String>,
+}
+
+/// what listings and `save_data` responses show about a stored record
+#[derive(Serialize, Debug, Clone)]
+pub struct RecordMeta {
+  pub id: String,
+  /// only set by the filesystem backend
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub filename: Option<String>,
+  pub date: String,
+  /// resolved client address, empty for append logs
+  pub addr: String,
+  /// RFC 3339, missing for files saved before records got unique ids
+  pub received_at: Option<String>,
+  pub kind: BodyKind,
+  /// bytes of the body before any compression at rest, so both backends report the same
+  pub size: u64,
+}
+
+/// what `append` stored, `bytes` and `created` are what `stats` counts for it
+pub struct Appended {
+  pub meta: RecordMeta,
+  pub bytes: u64,
+  /// whether a new record was started, rather than a line added to an existing log
+  pub created: bool,
+}
+
+/// usage of a single name
+#[derive(Serialize, Debug, Default)]
+pub struct NameStats {
+  pub name: String,
+  pub records: u64,
+  pub bytes: u64,
+}
+
+pub trait Storage: Send + Sync {
+  /// stores a new record under a fresh id
+  fn put(&self, record: &NewRecord) -> io::Result<RecordMeta>;
+
+  /// bytes and records `append` would add for `record`, checked against the quotas before appending
+  fn append_usage(&self, record: &NewRecord) -> io::Result<(u64, u64)> {
+    Ok((record.body.len() as u64, 1))
+  }
+
+  /// adds the record to a running log for its name, backends without such a log store a plain record
+  fn append(&self, record: &NewRecord) -> io::Result<Appended> {
+    let meta = self.put(record)?;
+    Ok(Appended {
+      bytes: meta.size,
+      created: true,
+      meta,
+    })
+  }
+
+  /// stores a new record whose body is read from `body` instead of `record.body`, which is ignored.
+  /// a failing reader must leave nothing behind
+  fn put_reader(&self, record: &NewRecord, body: &mut dyn Read) -> io::Result<RecordMeta> {
+    let mut buffer = vec![];
+    body.read_to_end(&mut buffer)?;
+    self.put(&NewRecord {
+      body: buffer,
+      ..record.clone()
+    })
+  }
+
+  /// body of a record, `None` when there is no such record for `name`
+  fn get(&self, name: &str, id: &str) -> io::Result<Option<(BodyKind, Vec<u8>)>>;
+
+  /// metadata of a record, `None` when there is no such record for `name`
+  fn metadata(&self, name: &str, id: &str) -> io::Result<Option<Metadata>>;
+
+  /// records of `name` ordered by id
+  fn list(&self, name: &str) -> io::Result<Vec<RecordMeta>>;
+
+  /// returns the bytes freed, as counted by `stats`, or `None` when there was nothing to delete
+  fn delete(&self, name: &str, id: &str) -> io::Result<Option<u64>>;
+
+  /// usage of every name that has records, ordered by name
+  fn stats(&self) -> io::Result<Vec<NameStats>>;
+
+  /// fails when the backend cannot be used right now, checked by `/readyz`
+  fn ping(&self) -> io::Result<()> {
+    Ok(())
+  }
+
+  /// makes everything written so far durable, called once on shutdown
+  fn flush(&self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+/// opens the configured backend
+pub fn open(config: &Config) -> io::Result<Arc<dyn Storage>> {
+  Ok(match config.storage.backend {
+    StorageBackend::Filesystem => Arc::new(FsStorage::open(&config.data_dir, config.storage.compression)?),
+    StorageBackend::Sqlite => Arc::new(SqliteStorage::open(&config.storage.sqlite_path(&config.data_dir))?),
+  })
+}
+
+/// per-process counter that keeps ids apart when several records land in the same millisecond
+pub fn next_seq() -> u64 {
+  static SEQ: AtomicU64 = AtomicU64::new(0);
+  SEQ.fetch_add(1, Ordering::Relaxed) % 10000
+}
+
+/// builds a record id like `{name}-{YYYY-MM-DD}-{addr}-{HHMMSSmmm}-{seq}`
+pub fn generate_id(name: &str, addr: &str, received_at: DateTime<Utc>, seq: u64) -> String {
+  format!(
+    "{}-{}-{}-{}-{:04}",
+    name,
+    received_at.format("%Y-%m-%d"),
+    addr.replace(['.', ':'], "_"),
+    received_at.format("%H%M%S%3f"),
+    seq
+  )
+}
+
+pub fn format_time(time: DateTime<Utc>) -> String {
+  time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+  format!("{:x}", Sha256::digest(bytes))
+}
+
+/// passes a body through while counting and hashing it, for backends that store bodies as they are read
+pub struct HashingReader<'a> {
+  inner: &'a mut dyn Read,
+  hasher: Sha256,
+  size: u64,
+}
+
+impl<'a> HashingReader<'a> {
+  pub fn new(inner: &'a mut dyn Read) -> Self {
+    HashingReader {
+      inner,
+      hasher: Sha256::new(),
+      size: 0,
+    }
+  }
+
+  /// size and hex encoded SHA-256 of everything read so far
+  pub fn finish(self) -> (u64, String) {
+    (self.size, format!("{:x}", self.hasher.finalize()))
+  }
+}
+
+impl Read for HashingReader<'_> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let n = self.inner.read(buf)?;
+    self.hasher.update(&buf[..n]);
+    self.size += n as u64;
+    Ok(n)
+  }
+}
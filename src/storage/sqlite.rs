@@ -0,0 +1,368 @@
+use super::{format_time, generate_id, next_seq, sha256_hex, BodyKind, Metadata, NameStats, NewRecord, RecordMeta, Storage};
+use rusqlite::{
+  params,
+  types::{self, ValueRef},
+  Connection, ErrorCode, OptionalExtension,
+};
+use std::{io, path::Path, sync::Mutex};
+
+/// all records in a single SQLite table, with their metadata in columns and the headers as a JSON object.
+/// JSON bodies are stored as TEXT so SQLite's JSON functions work on them, binary bodies as BLOB
+pub struct SqliteStorage {
+  conn: Mutex<Connection>,
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS records (
+  id TEXT PRIMARY KEY,
+  name TEXT NOT NULL,
+  received_at TEXT NOT NULL,
+  addr TEXT NOT NULL,
+  headers TEXT NOT NULL,
+  kind TEXT NOT NULL DEFAULT 'json',
+  peer TEXT,
+  sha256 TEXT,
+  client_subject TEXT,
+  body TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS records_by_name ON records (name, id);
+";
+
+fn kind_name(kind: BodyKind) -> &'static str {
+  match kind {
+    BodyKind::Json => "json",
+    BodyKind::Binary => "binary",
+    BodyKind::Lines => "lines",
+  }
+}
+
+fn kind_from_name(name: &str) -> BodyKind {
+  match name {
+    "binary" => BodyKind::Binary,
+    "lines" => BodyKind::Lines,
+    _ => BodyKind::Json,
+  }
+}
+
+/// rusqlite errors are surfaced to handlers as I/O errors, like failures of the filesystem backend
+fn to_io(e: rusqlite::Error) -> io::Error {
+  io::Error::other(e)
+}
+
+impl SqliteStorage {
+  pub fn open(path: &Path) -> io::Result<SqliteStorage> {
+    let conn = Connection::open(path).map_err(to_io)?;
+    conn.pragma_update(None, "journal_mode", "WAL").map_err(to_io)?;
+    conn.execute_batch(SCHEMA).map_err(to_io)?;
+    migrate(&conn).map_err(to_io)?;
+    Ok(SqliteStorage { conn: Mutex::new(conn) })
+  }
+
+  fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+    self.conn.lock().unwrap_or_else(|e| e.into_inner())
+  }
+}
+
+/// columns added after the first version of `SCHEMA`, older rows have NULL in the nullable ones
+const ADDED_COLUMNS: &[(&str, &str)] = &[
+  ("kind", "TEXT NOT NULL DEFAULT 'json'"),
+  ("peer", "TEXT"),
+  ("sha256", "TEXT"),
+  ("client_subject", "TEXT"),
+];
+
+/// brings databases created by older versions up to `SCHEMA`
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+  for (column, definition) in ADDED_COLUMNS {
+    let exists: bool = conn.query_row(
+      "SELECT COUNT(*) > 0 FROM pragma_table_info('records') WHERE name = ?1",
+      params![column],
+      |row| row.get(0),
+    )?;
+    if !exists {
+      conn.execute_batch(&format!("ALTER TABLE records ADD COLUMN {} {}", column, definition))?;
+    }
+  }
+  Ok(())
+}
+
+impl Storage for SqliteStorage {
+  fn put(&self, record: &NewRecord) -> io::Result<RecordMeta> {
+    let body = match record.kind {
+      BodyKind::Json | BodyKind::Lines => {
+        let text = std::str::from_utf8(&record.body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        types::Value::Text(text.to_owned())
+      }
+      BodyKind::Binary => types::Value::Blob(record.body.clone()),
+    };
+    let headers = serde_json::to_string(&record.headers)?;
+    let sha256 = sha256_hex(&record.body);
+    let received_at = format_time(record.received_at);
+
+    let conn = self.conn();
+    // a fresh sequence number is taken whenever the id is already in use, so nothing gets overwritten
+    let id = loop {
+      let id = generate_id(&record.name, &record.addr, record.received_at, next_seq());
+      let inserted = conn.execute(
+        "INSERT INTO records (id, name, received_at, addr, headers, kind, peer, sha256, client_subject, body)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+          id,
+          record.name,
+          received_at,
+          record.addr,
+          headers,
+          kind_name(record.kind),
+          record.peer,
+          sha256,
+          record.client_subject,
+          body
+        ],
+      );
+      match inserted {
+        Ok(_) => break id,
+        Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::ConstraintViolation => continue,
+        Err(e) => return Err(to_io(e)),
+      }
+    };
+
+    Ok(RecordMeta {
+      id,
+      filename: None,
+      date: received_at[..10].to_owned(),
+      addr: record.addr.clone(),
+      received_at: Some(received_at),
+      kind: record.kind,
+      size: record.body.len() as u64,
+    })
+  }
+
+  fn get(&self, name: &str, id: &str) -> io::Result<Option<(BodyKind, Vec<u8>)>> {
+    self
+      .conn()
+      .query_row(
+        "SELECT kind, body FROM records WHERE name = ?1 AND id = ?2",
+        params![name, id],
+        |row| {
+          let kind = kind_from_name(&row.get::<_, String>(0)?);
+          let body = match row.get_ref(1)? {
+            ValueRef::Text(bytes) | ValueRef::Blob(bytes) => bytes.to_vec(),
+            _ => vec![],
+          };
+          Ok((kind, body))
+        },
+      )
+      .optional()
+      .map_err(to_io)
+  }
+
+  fn metadata(&self, name: &str, id: &str) -> io::Result<Option<Metadata>> {
+    self
+      .conn()
+      .query_row(
+        "SELECT received_at, addr, peer, kind, headers, sha256, client_subject, body FROM records WHERE name = ?1 AND id = ?2",
+        params![name, id],
+        |row| {
+          let body = match row.get_ref(7)? {
+            ValueRef::Text(bytes) | ValueRef::Blob(bytes) => bytes,
+            _ => &[],
+          };
+          let headers: String = row.get(4)?;
+          Ok(Metadata {
+            received_at: Some(row.get(0)?),
+            addr: row.get(1)?,
+            peer: row.get(2)?,
+            client_subject: row.get(6)?,
+            kind: kind_from_name(&row.get::<_, String>(3)?),
+            size: body.len() as u64,
+            // rows from before the column existed
+            sha256: row.get::<_, Option<String>>(5)?.unwrap_or_else(|| sha256_hex(body)),
+            headers: serde_json::from_str(&headers).unwrap_or_default(),
+          })
+        },
+      )
+      .optional()
+      .map_err(to_io)
+  }
+
+  fn list(&self, name: &str) -> io::Result<Vec<RecordMeta>> {
+    let conn = self.conn();
+    let mut stmt = conn
+      .prepare("SELECT id, received_at, addr, kind, LENGTH(CAST(body AS BLOB)) FROM records WHERE name = ?1 ORDER BY id")
+      .map_err(to_io)?;
+    let rows = stmt
+      .query_map(params![name], |row| {
+        let received_at: String = row.get(1)?;
+        Ok(RecordMeta {
+          id: row.get(0)?,
+          filename: None,
+          date: received_at.get(..10).unwrap_or_default().to_owned(),
+          addr: row.get(2)?,
+          received_at: Some(received_at),
+          kind: kind_from_name(&row.get::<_, String>(3)?),
+          size: row.get(4)?,
+        })
+      })
+      .map_err(to_io)?;
+    rows.collect::<Result<_, _>>().map_err(to_io)
+  }
+
+  fn delete(&self, name: &str, id: &str) -> io::Result<Option<u64>> {
+    self
+      .conn()
+      .query_row(
+        "DELETE FROM records WHERE name = ?1 AND id = ?2 RETURNING LENGTH(CAST(body AS BLOB))",
+        params![name, id],
+        |row| row.get(0),
+      )
+      .optional()
+      .map_err(to_io)
+  }
+
+  fn stats(&self) -> io::Result<Vec<NameStats>> {
+    let conn = self.conn();
+    let mut stmt = conn
+      .prepare("SELECT name, COUNT(*), SUM(LENGTH(CAST(body AS BLOB))) FROM records GROUP BY name ORDER BY name")
+      .map_err(to_io)?;
+    let rows = stmt
+      .query_map([], |row| {
+        Ok(NameStats {
+          name: row.get(0)?,
+          records: row.get(1)?,
+          bytes: row.get(2)?,
+        })
+      })
+      .map_err(to_io)?;
+    rows.collect::<Result<_, _>>().map_err(to_io)
+  }
+
+  fn ping(&self) -> io::Result<()> {
+    self.conn().query_row("SELECT 1", [], |_| Ok(())).map_err(to_io)
+  }
+
+  /// moves the write-ahead log into the database file
+  fn flush(&self) -> io::Result<()> {
+    self
+      .conn()
+      .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+      .map_err(to_io)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::{TimeZone, Utc};
+
+  fn record(addr: &str, body: &[u8]) -> NewRecord {
+    NewRecord::test("lab", addr, Utc.with_ymd_and_hms(2024, 5, 6, 7, 8, 9).unwrap(), body)
+  }
+
+  #[test]
+  fn records_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = SqliteStorage::open(&dir.path().join("data.db")).unwrap();
+    let json = storage.put(&record("203.0.113.9", br#"{"a":1}"#)).unwrap();
+    let binary_body = [0u8, 159, 146, 150, 255];
+    let binary = storage
+      .put(&NewRecord {
+        kind: BodyKind::Binary,
+        ..record("2001:db8::1", &binary_body)
+      })
+      .unwrap();
+    storage.put(&NewRecord::test("other", "127.0.0.1", Utc::now(), b"[]")).unwrap();
+    assert_eq!((json.date.as_str(), json.size), ("2024-05-06", 7));
+    assert_ne!(json.id, binary.id);
+
+    assert_eq!(
+      storage.get("lab", &json.id).unwrap(),
+      Some((BodyKind::Json, br#"{"a":1}"#.to_vec()))
+    );
+    assert_eq!(
+      storage.get("lab", &binary.id).unwrap(),
+      Some((BodyKind::Binary, binary_body.to_vec()))
+    );
+    // records are only found under their own name
+    assert_eq!(storage.get("other", &json.id).unwrap(), None);
+
+    let listed: Vec<_> = storage
+      .list("lab")
+      .unwrap()
+      .into_iter()
+      .map(|meta| (meta.id, meta.addr, meta.kind, meta.size, meta.received_at))
+      .collect();
+    let received_at = Some("2024-05-06T07:08:09.000Z".to_owned());
+    let mut expected = vec![
+      (json.id.clone(), "203.0.113.9".to_owned(), BodyKind::Json, 7, received_at.clone()),
+      (binary.id.clone(), "2001:db8::1".to_owned(), BodyKind::Binary, 5, received_at),
+    ];
+    expected.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(listed, expected);
+
+    let metadata = storage.metadata("lab", &binary.id).unwrap().unwrap();
+    assert_eq!((metadata.kind, metadata.size), (BodyKind::Binary, 5));
+    assert_eq!(metadata.sha256, sha256_hex(&binary_body));
+    assert_eq!(metadata.peer.as_deref(), Some("127.0.0.1:1234"));
+
+    let stats: Vec<_> = storage.stats().unwrap().into_iter().map(|s| (s.name, s.records, s.bytes)).collect();
+    assert_eq!(stats, vec![("lab".to_owned(), 2, 12), ("other".to_owned(), 1, 2)]);
+
+    assert_eq!(storage.delete("lab", &binary.id).unwrap(), Some(5));
+    assert_eq!(storage.delete("lab", &binary.id).unwrap(), None);
+    assert_eq!(storage.get("lab", &binary.id).unwrap(), None);
+    assert_eq!(storage.list("lab").unwrap().len(), 1);
+  }
+
+  #[test]
+  fn older_databases_are_migrated() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    {
+      let conn = Connection::open(&path).unwrap();
+      conn
+        .execute_batch(
+          "CREATE TABLE records (
+             id TEXT PRIMARY KEY,
+             name TEXT NOT NULL,
+             received_at TEXT NOT NULL,
+             addr TEXT NOT NULL,
+             headers TEXT NOT NULL,
+             body TEXT NOT NULL
+           );
+           INSERT INTO records (id, name, received_at, addr, headers, body)
+           VALUES ('lab-old', 'lab', '2023-01-02T03:04:05.000Z', '198.51.100.7', '{\"user-agent\":\"curl\"}', '{\"old\":true}');",
+        )
+        .unwrap();
+    }
+
+    let storage = SqliteStorage::open(&path).unwrap();
+    let columns: Vec<String> = storage
+      .conn()
+      .prepare("SELECT name FROM pragma_table_info('records')")
+      .unwrap()
+      .query_map([], |row| row.get(0))
+      .unwrap()
+      .collect::<Result<_, _>>()
+      .unwrap();
+    for (column, _) in ADDED_COLUMNS {
+      assert!(columns.iter().any(|c| c == column), "{} missing", column);
+    }
+
+    let listed = storage.list("lab").unwrap();
+    assert_eq!(
+      (listed[0].id.as_str(), listed[0].kind, listed[0].size),
+      ("lab-old", BodyKind::Json, 12)
+    );
+    // rows without a hash get one computed from their body
+    let metadata = storage.metadata("lab", "lab-old").unwrap().unwrap();
+    assert_eq!(metadata.sha256, sha256_hex(br#"{"old":true}"#));
+    assert_eq!((metadata.peer, metadata.client_subject), (None, None));
+    assert_eq!(metadata.headers.get("user-agent").map(String::as_str), Some("curl"));
+
+    // new records go next to the old ones, and reopening migrates nothing twice
+    storage.put(&record("127.0.0.1", b"{}")).unwrap();
+    drop(storage);
+    let storage = SqliteStorage::open(&path).unwrap();
+    assert_eq!(storage.list("lab").unwrap().len(), 2);
+  }
+}
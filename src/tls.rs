@@ -0,0 +1,283 @@
+//! HTTPS for deployments without a reverse proxy, configured in `[tls]`: certificates reloaded when their files change
+//! or on SIGHUP, an optional redirect from plain HTTP and optional mutual TLS
+
+use crate::config::TlsConfig;
+use axum::{
+  extract::{ConnectInfo, Request},
+  http::{uri::Authority, HeaderMap, StatusCode, Uri},
+  response::{IntoResponse, Redirect, Response},
+  Router,
+};
+use core::net::SocketAddr;
+use hyper::body::Incoming;
+use hyper_util::{
+  rt::{TokioExecutor, TokioIo},
+  server::conn::auto,
+  service::TowerToHyperService,
+};
+use std::{
+  fs::{self, File},
+  future::Future,
+  io::{self, BufReader},
+  path::{Path, PathBuf},
+  sync::{Arc, RwLock},
+  time::{Duration, SystemTime},
+};
+use tokio::{
+  net::{TcpListener, TcpStream},
+  sync::{mpsc, watch},
+};
+use tokio_rustls::{
+  rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+  },
+  TlsAcceptor,
+};
+use tower::ServiceExt;
+use tracing::{debug, error, info};
+
+/// clients that have not finished the handshake by then are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// the verified client certificate of a mutual TLS connection, stored in request extensions
+#[derive(Clone, Debug)]
+pub struct ClientCert {
+  /// distinguished name, like `CN=sensor-1, O=Lab`
+  pub subject: String,
+}
+
+fn invalid_data(path: &Path, e: impl std::fmt::Display) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+  File::open(path)
+    .map(BufReader::new)
+    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+  let certs = rustls_pemfile::certs(&mut open(path)?)
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| invalid_data(path, e))?;
+  if certs.is_empty() {
+    return Err(invalid_data(path, "no PEM certificate found"));
+  }
+  Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+  rustls_pemfile::private_key(&mut open(path)?)
+    .map_err(|e| invalid_data(path, e))?
+    .ok_or_else(|| invalid_data(path, "no PEM private key found"))
+}
+
+/// reads the certificate files into a rustls configuration, HTTP/2 is offered next to HTTP/1.1
+fn server_config(tls: &TlsConfig) -> io::Result<Arc<ServerConfig>> {
+  let builder = ServerConfig::builder();
+  let builder = match &tls.client_ca {
+    Some(path) => {
+      let mut roots = RootCertStore::empty();
+      for cert in load_certs(path)? {
+        roots.add(cert).map_err(|e| invalid_data(path, e))?;
+      }
+      let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+      let verifier = if tls.require_client_cert {
+        verifier
+      } else {
+        verifier.allow_unauthenticated()
+      };
+      builder.with_client_cert_verifier(verifier.build().map_err(|e| invalid_data(path, e))?)
+    }
+    None => builder.with_no_client_auth(),
+  };
+
+  let mut config = builder
+    .with_single_cert(load_certs(&tls.cert)?, load_key(&tls.key)?)
+    .map_err(|e| invalid_data(&tls.key, e))?;
+  config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+  Ok(Arc::new(config))
+}
+
+/// the certificates new connections are accepted with, swapped when they are reloaded
+pub struct Certificates {
+  tls: TlsConfig,
+  current: RwLock<Arc<ServerConfig>>,
+}
+
+impl Certificates {
+  pub fn load(tls: &TlsConfig) -> io::Result<Arc<Certificates>> {
+    Ok(Arc::new(Certificates {
+      tls: tls.clone(),
+      current: RwLock::new(server_config(tls)?),
+    }))
+  }
+
+  fn current(&self) -> Arc<ServerConfig> {
+    self.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+  }
+
+  /// a broken set of files is reported and the previous certificates stay in use
+  fn reload(&self, reason: &str) {
+    match server_config(&self.tls) {
+      Ok(config) => {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = config;
+        info!("Reloaded TLS certificates after {}", reason);
+      }
+      Err(e) => error!("Failed to reload TLS certificates, keeping the previous ones: {}", e),
+    }
+  }
+
+  fn files(&self) -> Vec<PathBuf> {
+    [Some(&self.tls.cert), Some(&self.tls.key), self.tls.client_ca.as_ref()]
+      .into_iter()
+      .flatten()
+      .cloned()
+      .collect()
+  }
+
+  /// modification times of the files, to notice when they are replaced
+  fn modified(&self) -> Vec<Option<SystemTime>> {
+    self
+      .files()
+      .iter()
+      .map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+      .collect()
+  }
+}
+
+/// reloads the certificates on SIGHUP and every `reload_interval` seconds when one of the files changed.
+/// a certificate and its key are often replaced one after the other, a reload failing in between is retried with the
+/// next change
+pub fn watch(certs: Arc<Certificates>) {
+  tokio::spawn(async move {
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).expect("failed to listen for SIGHUP");
+    let mut interval = tokio::time::interval(Duration::from_secs(certs.tls.reload_interval.max(1)));
+    let mut seen = certs.modified();
+    loop {
+      #[cfg(unix)]
+      let hangup = hangup.recv();
+      #[cfg(not(unix))]
+      let hangup = std::future::pending::<Option<()>>();
+
+      tokio::select! {
+        _ = hangup => certs.reload("SIGHUP"),
+        _ = interval.tick() => {
+          let modified = certs.modified();
+          if modified != seen {
+            certs.reload("a file change");
+            seen = modified;
+          }
+        }
+      }
+    }
+  });
+}
+
+/// `CN=..., O=...` of a certificate that rustls already verified
+fn subject(cert: &CertificateDer<'_>) -> Option<String> {
+  let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+  Some(cert.subject().to_string())
+}
+
+/// accepts HTTPS connections until `stopped` resolves, then waits for the open ones to finish their requests.
+/// requests get the same `ConnectInfo` as with `axum::serve`, and the `ClientCert` when the client sent one
+pub async fn serve(listener: TcpListener, app: Router, certs: Arc<Certificates>, stopped: impl Future<Output = ()>) -> io::Result<()> {
+  let (closing_tx, closing) = watch::channel(false);
+  // every connection holds a sender, so `recv` returns once all of them are closed
+  let (open_tx, mut open) = mpsc::channel::<()>(1);
+
+  tokio::pin!(stopped);
+  loop {
+    let (tcp, peer) = tokio::select! {
+      accepted = listener.accept() => match accepted {
+        Ok(accepted) => accepted,
+        Err(e) => {
+          // usually out of file descriptors, which only gets better once connections close
+          error!("Failed to accept connection: {}", e);
+          tokio::time::sleep(Duration::from_secs(1)).await;
+          continue;
+        }
+      },
+      _ = &mut stopped => break,
+    };
+    let acceptor = TlsAcceptor::from(certs.current());
+    tokio::spawn(connection(tcp, peer, acceptor, app.clone(), closing.clone(), open_tx.clone()));
+  }
+
+  drop(listener);
+  let _ = closing_tx.send(true);
+  drop(open_tx);
+  let _ = open.recv().await;
+  Ok(())
+}
+
+async fn connection(
+  tcp: TcpStream,
+  peer: SocketAddr,
+  acceptor: TlsAcceptor,
+  app: Router,
+  mut closing: watch::Receiver<bool>,
+  _open: mpsc::Sender<()>,
+) {
+  let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+    Ok(Ok(stream)) => stream,
+    Ok(Err(e)) => {
+      debug!(%peer, "TLS handshake failed: {}", e);
+      return;
+    }
+    Err(_) => return,
+  };
+  let client_cert = stream
+    .get_ref()
+    .1
+    .peer_certificates()
+    .and_then(|certs| certs.first())
+    .and_then(subject)
+    .map(|subject| ClientCert { subject });
+
+  let service = app.map_request(move |mut req: Request<Incoming>| {
+    req.extensions_mut().insert(ConnectInfo(peer));
+    if let Some(client_cert) = &client_cert {
+      req.extensions_mut().insert(client_cert.clone());
+    }
+    req
+  });
+  let builder = auto::Builder::new(TokioExecutor::new());
+  let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service));
+  tokio::pin!(conn);
+
+  tokio::select! {
+    _ = conn.as_mut() => return,
+    _ = closing.wait_for(|&closing| closing) => conn.as_mut().graceful_shutdown(),
+  }
+  // idle connections close right away, busy ones after their current request
+  let _ = conn.await;
+}
+
+/// answers every request with a permanent redirect to the same path on `https_port`, until `stopped` resolves
+pub async fn redirect(listener: TcpListener, https_port: u16, stopped: impl Future<Output = ()> + Send + 'static) -> io::Result<()> {
+  let app = Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move { redirect_to_https(&headers, &uri, https_port) });
+  axum::serve(listener, app).with_graceful_shutdown(stopped).await
+}
+
+fn redirect_to_https(headers: &HeaderMap, uri: &Uri, https_port: u16) -> Response {
+  let host = uri.authority().map(|authority| authority.host().to_owned()).or_else(|| {
+    let host = headers.get("host")?.to_str().ok()?;
+    Some(host.parse::<Authority>().ok()?.host().to_owned())
+  });
+  let Some(host) = host else {
+    return (StatusCode::BAD_REQUEST, "Host header required").into_response();
+  };
+
+  let path = uri.path_and_query().map_or("/", |path| path.as_str());
+  let location = match https_port {
+    443 => format!("https://{}{}", host, path),
+    port => format!("https://{}:{}{}", host, port, path),
+  };
+  // 308 keeps the method and body, so a POST is repeated over HTTPS
+  Redirect::permanent(&location).into_response()
+}
@@ -0,0 +1,690 @@
+//! notifications POSTed to `[webhooks]` endpoints when records are saved. deliveries are appended to
+//! `{data_dir}/.webhooks.jsonl` before they are attempted, so they survive restarts, and are retried with exponential
+//! backoff until they succeed or run out of attempts and become dead letters
+
+use crate::{
+  config::{matches_name, SaveMode, WebhookEndpoint, WebhooksConfig},
+  durable::{now, JsonLog},
+  error::AppError,
+  storage::{Metadata, NewRecord, RecordMeta},
+  AppState,
+};
+use axum::{
+  extract::{Path as UrlPath, State},
+  http::StatusCode,
+  Json,
+};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::{
+  collections::{BTreeMap, HashSet},
+  io,
+  path::Path,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
+use tokio::sync::{Notify, Semaphore};
+use tracing::{debug, error, info, warn};
+
+const LOG_FILENAME: &str = ".webhooks.jsonl";
+const EVENT: &str = "record.saved";
+const EVENT_HEADER: &str = "x-data-backs-event";
+const DELIVERY_HEADER: &str = "x-data-backs-delivery";
+/// unix seconds, part of the signed message so a captured request cannot be replayed later
+const TIMESTAMP_HEADER: &str = "x-data-backs-timestamp";
+/// `sha256=` then the hex encoded HMAC-SHA256 of `{timestamp}.{body}`
+const SIGNATURE_HEADER: &str = "x-data-backs-signature";
+/// longest wait of the worker when nothing is due, new deliveries wake it up anyway
+const IDLE_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+  Pending,
+  /// out of attempts, waits for an admin to retry or discard it
+  Dead,
+  /// delivered or discarded, only found in the log until it is compacted
+  Done,
+}
+
+/// a notification for one endpoint, one line of the log per change
+#[derive(Serialize, Deserialize, Clone)]
+struct Delivery {
+  id: String,
+  /// id of the endpoint in `[webhooks]`, its url and secret are looked up when sending
+  endpoint: String,
+  status: Status,
+  /// unix seconds
+  created_at: i64,
+  attempts: u32,
+  /// unix seconds, when the next attempt is due
+  next_attempt: i64,
+  /// why the last attempt failed
+  last_error: Option<String>,
+  /// the body that is sent
+  notification: Value,
+}
+
+struct Inner {
+  /// pending and dead deliveries by id, ids sort by creation
+  deliveries: BTreeMap<String, Delivery>,
+  /// being sent right now
+  in_flight: HashSet<String>,
+  log: JsonLog,
+}
+
+/// the queue of deliveries and the client sending them
+pub struct Webhooks {
+  config: WebhooksConfig,
+  client: reqwest::Client,
+  inner: Mutex<Inner>,
+  /// wakes the worker when a delivery was queued or a send finished
+  wake: Notify,
+}
+
+/// sortable and unique within the process, like `20240801T120000123-000042`
+fn next_id() -> String {
+  static SEQ: AtomicU64 = AtomicU64::new(0);
+  format!(
+    "{}-{:06}",
+    chrono::Utc::now().format("%Y%m%dT%H%M%S%3f"),
+    SEQ.fetch_add(1, Ordering::Relaxed) % 1_000_000
+  )
+}
+
+/// hex encoded HMAC-SHA256 of `{timestamp}.{body}`
+fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+  let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+  mac.update(timestamp.to_string().as_bytes());
+  mac.update(b".");
+  mac.update(body);
+  format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// the error with its causes, reqwest keeps the interesting part like "connection refused" in the sources
+fn describe(e: &dyn std::error::Error) -> String {
+  let mut message = e.to_string();
+  let mut source = e.source();
+  while let Some(cause) = source {
+    // some errors repeat their source in their own message
+    let cause_message = cause.to_string();
+    if !message.contains(&cause_message) {
+      message.push_str(": ");
+      message.push_str(&cause_message);
+    }
+    source = cause.source();
+  }
+  message
+}
+
+impl Webhooks {
+  /// loads the pending and dead deliveries of a previous run, dropping finished ones from the log
+  pub fn open(dir: &Path, config: &WebhooksConfig) -> io::Result<Webhooks> {
+    let mut deliveries = BTreeMap::new();
+    // the last line of a delivery tells its state, a line cut short by a crash leaves the one before it
+    let mut log = JsonLog::open(dir, LOG_FILENAME, |delivery: Delivery| {
+      if delivery.status == Status::Done {
+        deliveries.remove(&delivery.id);
+      } else {
+        deliveries.insert(delivery.id.clone(), delivery);
+      }
+    })?;
+    log.rewrite(deliveries.values())?;
+
+    let client = reqwest::Client::builder()
+      .timeout(Duration::from_secs(config.timeout))
+      .user_agent(concat!("data-backs/", env!("CARGO_PKG_VERSION")))
+      .build()
+      .map_err(io::Error::other)?;
+    Ok(Webhooks {
+      config: config.clone(),
+      client,
+      inner: Mutex::new(Inner {
+        deliveries,
+        in_flight: HashSet::new(),
+        log,
+      }),
+      wake: Notify::new(),
+    })
+  }
+
+  fn inner(&self) -> std::sync::MutexGuard<'_, Inner> {
+    self.inner.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  /// pending and dead deliveries
+  pub fn len(&self) -> usize {
+    self.inner().deliveries.len()
+  }
+
+  fn endpoint(&self, id: &str) -> Option<&WebhookEndpoint> {
+    self.config.endpoints.iter().find(|endpoint| endpoint.id == id)
+  }
+
+  /// appends the deliveries as they are now to the log, compacting it when it holds too many superseded lines
+  fn persist(&self, inner: &mut Inner, changed: &[Delivery]) -> io::Result<()> {
+    inner.log.append(changed)?;
+    if inner.log.needs_compaction(inner.deliveries.len()) {
+      inner.log.rewrite(inner.deliveries.values())?;
+    }
+    Ok(())
+  }
+
+  /// queues a notification of a stored record for every endpoint whose names include it. `metadata` is only built
+  /// when there is one, JSON payloads are sent along to endpoints with `include_payload` when `record.body` holds them
+  pub fn notify(&self, record: &NewRecord, meta: &RecordMeta, mode: SaveMode, metadata: impl FnOnce() -> Metadata) {
+    let endpoints: Vec<_> = self
+      .config
+      .endpoints
+      .iter()
+      .filter(|endpoint| endpoint.names.iter().any(|pattern| matches_name(pattern, &record.name)))
+      .collect();
+    if endpoints.is_empty() {
+      return;
+    }
+
+    let metadata = metadata();
+    let notification = json!({
+      "event": EVENT,
+      "name": record.name,
+      "id": meta.id,
+      "mode": mode,
+      "metadata": metadata,
+    });
+    // streamed bodies never were in memory, their payload has to be fetched from `/data/{name}/{id}`
+    let payload = (metadata.kind == crate::storage::BodyKind::Json && !record.body.is_empty())
+      .then(|| serde_json::from_slice::<Value>(&record.body).ok())
+      .flatten();
+
+    let created_at = now();
+    let deliveries: Vec<_> = endpoints
+      .into_iter()
+      .map(|endpoint| {
+        let mut notification = notification.clone();
+        if let Some(payload) = payload.as_ref().filter(|_| endpoint.include_payload) {
+          notification["payload"] = payload.clone();
+        }
+        Delivery {
+          id: next_id(),
+          endpoint: endpoint.id.clone(),
+          status: Status::Pending,
+          created_at,
+          attempts: 0,
+          next_attempt: created_at,
+          last_error: None,
+          notification,
+        }
+      })
+      .collect();
+
+    let mut inner = self.inner();
+    for delivery in &deliveries {
+      inner.deliveries.insert(delivery.id.clone(), delivery.clone());
+    }
+    // the record is saved at this point, so the deliveries are still attempted while this process runs
+    if let Err(e) = self.persist(&mut inner, &deliveries) {
+      error!("Failed to persist webhook deliveries: {}", e);
+    }
+    drop(inner);
+    self.wake.notify_one();
+  }
+
+  /// marks up to `limit` due deliveries as in flight and returns them, with how long to wait before the next one is due
+  fn take_due(&self, limit: usize) -> (Vec<Delivery>, Duration) {
+    let now = now();
+    let mut inner = self.inner();
+    let Inner { deliveries, in_flight, .. } = &mut *inner;
+
+    let mut due = vec![];
+    let mut next = None::<i64>;
+    for delivery in deliveries.values() {
+      if delivery.status != Status::Pending || in_flight.contains(&delivery.id) {
+        continue;
+      }
+      if delivery.next_attempt > now {
+        next = Some(next.map_or(delivery.next_attempt, |next| next.min(delivery.next_attempt)));
+      } else if due.len() < limit {
+        due.push(delivery.clone());
+      } else {
+        // the rest waits for a send to finish, which wakes the worker
+        next = Some(now + IDLE_WAIT.as_secs() as i64);
+      }
+    }
+    for delivery in &due {
+      in_flight.insert(delivery.id.clone());
+    }
+
+    let wait = next.map_or(IDLE_WAIT, |next| Duration::from_secs(next.saturating_sub(now).max(1) as u64));
+    (due, wait.min(IDLE_WAIT))
+  }
+
+  /// POSTs a delivery to its endpoint, `Err` tells why it has to be tried again
+  async fn send(&self, delivery: &Delivery) -> Result<(), String> {
+    let endpoint = self
+      .endpoint(&delivery.endpoint)
+      .ok_or_else(|| format!("endpoint {:?} is no longer configured", delivery.endpoint))?;
+    let body = serde_json::to_vec(&delivery.notification).map_err(|e| e.to_string())?;
+    let timestamp = now();
+    let signature = sign(&endpoint.secret, timestamp, &body);
+
+    let response = self
+      .client
+      .post(&endpoint.url)
+      .header(reqwest::header::CONTENT_TYPE, "application/json")
+      .header(EVENT_HEADER, EVENT)
+      .header(DELIVERY_HEADER, &delivery.id)
+      .header(TIMESTAMP_HEADER, timestamp)
+      .header(SIGNATURE_HEADER, format!("sha256={}", signature))
+      .body(body)
+      .send()
+      .await
+      .map_err(|e| describe(&e))?;
+    let status = response.status();
+    if !status.is_success() {
+      return Err(format!("endpoint answered {}", status));
+    }
+    Ok(())
+  }
+
+  /// seconds to wait after `attempts` failed attempts
+  fn backoff(&self, attempts: u32) -> u64 {
+    let factor = 1u64.checked_shl(attempts.saturating_sub(1)).unwrap_or(u64::MAX);
+    self
+      .config
+      .initial_backoff
+      .saturating_mul(factor)
+      .min(self.config.max_backoff.max(1))
+  }
+
+  /// records the outcome of an attempt: done, retried later or, out of attempts, a dead letter
+  fn finish(&self, id: &str, result: Result<(), String>) {
+    let mut inner = self.inner();
+    inner.in_flight.remove(id);
+    let Some(mut delivery) = inner.deliveries.remove(id) else {
+      return;
+    };
+    delivery.attempts += 1;
+
+    let mut changed = vec![];
+    match result {
+      Ok(()) => {
+        debug!(
+          delivery = delivery.id,
+          endpoint = delivery.endpoint,
+          attempts = delivery.attempts,
+          "Webhook delivered"
+        );
+        delivery.status = Status::Done;
+        delivery.last_error = None;
+      }
+      Err(e) if delivery.attempts >= self.config.max_attempts => {
+        warn!(
+          delivery = delivery.id,
+          endpoint = delivery.endpoint,
+          attempts = delivery.attempts,
+          "Webhook failed for good: {}",
+          e
+        );
+        delivery.status = Status::Dead;
+        delivery.last_error = Some(e);
+      }
+      Err(e) => {
+        let backoff = self.backoff(delivery.attempts);
+        info!(
+          delivery = delivery.id,
+          endpoint = delivery.endpoint,
+          attempts = delivery.attempts,
+          "Webhook failed, retrying in {} seconds: {}",
+          backoff,
+          e
+        );
+        delivery.next_attempt = now().saturating_add(backoff as i64);
+        delivery.last_error = Some(e);
+      }
+    }
+    if delivery.status != Status::Done {
+      inner.deliveries.insert(delivery.id.clone(), delivery.clone());
+    }
+    changed.push(delivery);
+
+    // the oldest dead letters make room for new ones
+    let dead: Vec<_> = inner
+      .deliveries
+      .values()
+      .filter(|delivery| delivery.status == Status::Dead)
+      .map(|delivery| delivery.id.clone())
+      .collect();
+    for id in dead.iter().take(dead.len().saturating_sub(self.config.max_dead)) {
+      if let Some(mut dropped) = inner.deliveries.remove(id) {
+        warn!(
+          delivery = dropped.id,
+          endpoint = dropped.endpoint,
+          "Dropping dead webhook delivery, max_dead reached"
+        );
+        dropped.status = Status::Done;
+        changed.push(dropped);
+      }
+    }
+
+    if let Err(e) = self.persist(&mut inner, &changed) {
+      error!("Failed to persist webhook deliveries: {}", e);
+    }
+  }
+
+  /// dead letters, oldest first
+  fn dead(&self) -> Vec<Delivery> {
+    let inner = self.inner();
+    inner
+      .deliveries
+      .values()
+      .filter(|delivery| delivery.status == Status::Dead)
+      .cloned()
+      .collect()
+  }
+
+  /// gives a dead letter a fresh set of attempts, or drops it for good. `None` when there is no such dead letter
+  fn resolve(&self, id: &str, retry: bool) -> io::Result<Option<Delivery>> {
+    let mut inner = self.inner();
+    let Some(delivery) = inner.deliveries.get_mut(id).filter(|delivery| delivery.status == Status::Dead) else {
+      return Ok(None);
+    };
+    if retry {
+      delivery.status = Status::Pending;
+      delivery.attempts = 0;
+      delivery.next_attempt = now();
+    } else {
+      delivery.status = Status::Done;
+    }
+    let delivery = delivery.clone();
+    if !retry {
+      inner.deliveries.remove(id);
+    }
+    self.persist(&mut inner, std::slice::from_ref(&delivery))?;
+    drop(inner);
+    self.wake.notify_one();
+    Ok(Some(delivery))
+  }
+}
+
+/// background task sending due deliveries, at most `concurrency` at a time.
+/// deliveries cut short by a shutdown are still pending in the log and sent again after the restart
+pub fn spawn(state: Arc<AppState>) {
+  if state.config.webhooks.endpoints.is_empty() && state.webhooks.len() == 0 {
+    return;
+  }
+  tokio::spawn(async move {
+    let permits = Arc::new(Semaphore::new(state.config.webhooks.concurrency));
+    loop {
+      let (due, wait) = state.webhooks.take_due(permits.available_permits());
+      for delivery in due {
+        let permit = permits
+          .clone()
+          .try_acquire_owned()
+          .expect("no more due deliveries than free permits");
+        let state = state.clone();
+        tokio::spawn(async move {
+          let result = state.webhooks.send(&delivery).await;
+          state.webhooks.finish(&delivery.id, result);
+          drop(permit);
+          state.webhooks.wake.notify_one();
+        });
+      }
+      tokio::select! {
+        _ = tokio::time::sleep(wait) => {}
+        _ = state.webhooks.wake.notified() => {}
+      }
+    }
+  });
+}
+
+/// `GET /webhooks/dead`, deliveries that ran out of attempts with the error of the last one
+pub async fn list_dead(State(state): State<Arc<AppState>>) -> Json<Value> {
+  Json(json!({ "deliveries": state.webhooks.dead() }))
+}
+
+/// `POST /webhooks/dead/{id}/retry`, queues a dead letter again with a fresh set of attempts
+pub async fn retry_dead(State(state): State<Arc<AppState>>, UrlPath(id): UrlPath<String>) -> Result<Json<Value>, AppError> {
+  match state
+    .webhooks
+    .resolve(&id, true)
+    .map_err(AppError::io("queueing webhook delivery"))?
+  {
+    Some(delivery) => Ok(Json(json!(delivery))),
+    None => Err(AppError::NotFound("Dead webhook delivery".to_owned())),
+  }
+}
+
+/// `DELETE /webhooks/dead/{id}`, drops a dead letter for good
+pub async fn discard_dead(State(state): State<Arc<AppState>>, UrlPath(id): UrlPath<String>) -> Result<StatusCode, AppError> {
+  match state
+    .webhooks
+    .resolve(&id, false)
+    .map_err(AppError::io("discarding webhook delivery"))?
+  {
+    Some(_) => Ok(StatusCode::NO_CONTENT),
+    None => Err(AppError::NotFound("Dead webhook delivery".to_owned())),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::BodyKind;
+  use axum::{extract::State, http::HeaderMap, routing::post, Router};
+  use std::sync::atomic::AtomicU16;
+  use tokio::sync::mpsc;
+
+  const SECRET: &str = "test-secret";
+
+  /// what the stand-in receiver got
+  struct Received {
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+  }
+
+  /// an HTTP server on a free local port answering every POST with `status`, returns its url
+  async fn receiver(status: Arc<AtomicU16>) -> (String, mpsc::UnboundedReceiver<Received>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let app = Router::new()
+      .route(
+        "/hook",
+        post(
+          |State((status, tx)): State<(Arc<AtomicU16>, mpsc::UnboundedSender<Received>)>,
+           headers: HeaderMap,
+           body: axum::body::Bytes| async move {
+            let _ = tx.send(Received { headers, body });
+            StatusCode::from_u16(status.load(Ordering::Relaxed)).unwrap()
+          },
+        ),
+      )
+      .with_state((status, tx));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    (url, rx)
+  }
+
+  fn config(url: &str) -> WebhooksConfig {
+    WebhooksConfig {
+      endpoints: vec![WebhookEndpoint {
+        id: "test".to_owned(),
+        url: url.to_owned(),
+        secret: SECRET.to_owned(),
+        names: vec!["lab-*".to_owned()],
+        include_payload: true,
+      }],
+      max_attempts: 3,
+      initial_backoff: 10,
+      max_backoff: 15,
+      timeout: 5,
+      ..WebhooksConfig::default()
+    }
+  }
+
+  fn notify(webhooks: &Webhooks, name: &str) {
+    let record = NewRecord::test(name, "203.0.113.9", chrono::Utc::now(), br#"{"t":1}"#);
+    let meta = RecordMeta {
+      id: format!("{}-1", name),
+      filename: None,
+      date: String::new(),
+      addr: record.addr.clone(),
+      received_at: None,
+      kind: BodyKind::Json,
+      size: record.body.len() as u64,
+    };
+    webhooks.notify(&record, &meta, SaveMode::File, || record.metadata(meta.size, String::new()));
+  }
+
+  /// sends every due delivery once, like the worker does
+  async fn attempt(webhooks: &Webhooks) -> usize {
+    let (due, _) = webhooks.take_due(8);
+    for delivery in &due {
+      let result = webhooks.send(delivery).await;
+      webhooks.finish(&delivery.id, result);
+    }
+    due.len()
+  }
+
+  /// makes every pending delivery due now instead of after its backoff
+  fn skip_backoff(webhooks: &Webhooks) -> Vec<i64> {
+    let now = now();
+    let mut inner = webhooks.inner();
+    inner
+      .deliveries
+      .values_mut()
+      .map(|delivery| std::mem::replace(&mut delivery.next_attempt, now) - now)
+      .collect()
+  }
+
+  #[tokio::test]
+  async fn deliveries_are_signed() {
+    let dir = tempfile::tempdir().unwrap();
+    let (url, mut received) = receiver(Arc::new(AtomicU16::new(204))).await;
+    let webhooks = Webhooks::open(dir.path(), &config(&url)).unwrap();
+
+    notify(&webhooks, "other");
+    assert_eq!(webhooks.len(), 0);
+    notify(&webhooks, "lab-1");
+    assert_eq!(attempt(&webhooks).await, 1);
+    assert_eq!(webhooks.len(), 0);
+
+    let Received { headers, body } = received.recv().await.unwrap();
+    let header = |name: &str| headers.get(name).unwrap().to_str().unwrap().to_owned();
+    assert_eq!(header(EVENT_HEADER), EVENT);
+    assert!(!header(DELIVERY_HEADER).is_empty());
+
+    // what a receiver does: recompute the HMAC over the timestamp and the raw body
+    let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+    mac.update(format!("{}.", header(TIMESTAMP_HEADER)).as_bytes());
+    mac.update(&body);
+    let expected = format!("sha256={:x}", mac.finalize().into_bytes());
+    assert_eq!(header(SIGNATURE_HEADER), expected);
+    assert!((now() - header(TIMESTAMP_HEADER).parse::<i64>().unwrap()).abs() <= 1);
+
+    let notification: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(notification["name"], "lab-1");
+    assert_eq!(notification["id"], "lab-1-1");
+    assert_eq!(notification["payload"], json!({ "t": 1 }));
+    assert_eq!(notification["metadata"]["addr"], "203.0.113.9");
+  }
+
+  #[tokio::test]
+  async fn failures_back_off_then_become_dead_letters() {
+    let dir = tempfile::tempdir().unwrap();
+    let status = Arc::new(AtomicU16::new(500));
+    let (url, _received) = receiver(status.clone()).await;
+    let webhooks = Webhooks::open(dir.path(), &config(&url)).unwrap();
+
+    notify(&webhooks, "lab-1");
+    assert_eq!(attempt(&webhooks).await, 1);
+    // not due again before its backoff
+    assert_eq!(attempt(&webhooks).await, 0);
+    let waits = skip_backoff(&webhooks);
+    assert!((9..=10).contains(&waits[0]), "{:?}", waits);
+
+    assert_eq!(attempt(&webhooks).await, 1);
+    // doubled, but capped by max_backoff
+    let waits = skip_backoff(&webhooks);
+    assert!((14..=15).contains(&waits[0]), "{:?}", waits);
+
+    assert_eq!(attempt(&webhooks).await, 1);
+    let dead = webhooks.dead();
+    assert_eq!(dead.len(), 1);
+    assert_eq!(dead[0].attempts, 3);
+    assert_eq!(dead[0].last_error.as_deref(), Some("endpoint answered 500 Internal Server Error"));
+    // dead letters are not attempted any more
+    assert_eq!(attempt(&webhooks).await, 0);
+
+    // until they are retried with a fresh set of attempts
+    status.store(200, Ordering::Relaxed);
+    webhooks.resolve(&dead[0].id, true).unwrap().unwrap();
+    assert_eq!(attempt(&webhooks).await, 1);
+    assert!(webhooks.dead().is_empty());
+    assert_eq!(webhooks.len(), 0);
+  }
+
+  #[test]
+  fn backoff_doubles_up_to_the_maximum() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = WebhooksConfig {
+      initial_backoff: 10,
+      max_backoff: 3600,
+      ..WebhooksConfig::default()
+    };
+    let webhooks = Webhooks::open(dir.path(), &config).unwrap();
+    let backoffs: Vec<_> = [1, 2, 3, 9, 64, u32::MAX]
+      .into_iter()
+      .map(|attempts| webhooks.backoff(attempts))
+      .collect();
+    assert_eq!(backoffs, [10, 20, 40, 2560, 3600, 3600]);
+  }
+
+  #[tokio::test]
+  async fn the_queue_is_replayed_after_a_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    // nothing listens there, every attempt fails
+    let config = WebhooksConfig {
+      max_attempts: 1,
+      ..config("http://127.0.0.1:9/hook")
+    };
+    {
+      let webhooks = Webhooks::open(dir.path(), &config).unwrap();
+      notify(&webhooks, "lab-discarded");
+      notify(&webhooks, "lab-dead");
+      assert_eq!(attempt(&webhooks).await, 2);
+      let dead = webhooks.dead();
+      assert!(dead[0].last_error.is_some());
+      webhooks.resolve(&dead[0].id, false).unwrap().unwrap();
+
+      notify(&webhooks, "lab-in-flight");
+      // taken by the worker, which is stopped before the endpoint answers
+      assert_eq!(webhooks.take_due(8).0.len(), 1);
+      notify(&webhooks, "lab-pending");
+    }
+
+    let webhooks = Webhooks::open(dir.path(), &config).unwrap();
+    let states: BTreeMap<_, _> = webhooks
+      .inner()
+      .deliveries
+      .values()
+      .map(|delivery| (delivery.notification["name"].as_str().unwrap().to_owned(), delivery.status))
+      .collect();
+    let expected = [
+      ("lab-dead", Status::Dead),
+      ("lab-in-flight", Status::Pending),
+      ("lab-pending", Status::Pending),
+    ];
+    assert_eq!(states, expected.map(|(name, status)| (name.to_owned(), status)).into());
+    // including the one that was in flight
+    assert_eq!(webhooks.take_due(8).0.len(), 2);
+
+    // the log was compacted to the live deliveries
+    let lines = std::fs::read_to_string(dir.path().join(LOG_FILENAME)).unwrap();
+    assert_eq!(lines.lines().count(), 3);
+  }
+}